@@ -1,16 +1,27 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{watch, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 use parking_lot::Mutex;
-use reqwest::RequestBuilder;
 use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use serenity::async_trait;
-use serenity::model::application::interaction::{Interaction, autocomplete::AutocompleteInteraction, application_command::ApplicationCommandInteraction, message_component::MessageComponentInteraction};
+use serenity::model::application::interaction::{Interaction, InteractionResponseType, autocomplete::AutocompleteInteraction, application_command::{ApplicationCommandInteraction, CommandDataOptionValue}, message_component::MessageComponentInteraction, modal::ModalSubmitInteraction};
+use serenity::builder::{CreateApplicationCommands, CreateComponents, CreateEmbed, CreateInteractionResponseData};
+use serenity::model::application::ApplicationFlags;
+use serenity::model::application::component::{ActionRowComponent, ButtonStyle, InputTextStyle};
 use serenity::model::prelude::command::{Command, CommandOptionType};
-use serenity::model::prelude::{UserId, Ready};
+use serenity::model::prelude::{Attachment, Channel, ChannelId, ChannelType, GuildId, MessageId, Permissions, RoleId, User, UserId, Ready};
 use serenity::prelude::*;
-use serenity::model::channel::Message;
+use serenity::model::channel::{AttachmentType, Message, MessageFlags, MessageType, Reaction};
+use serenity::client::bridge::gateway::{ShardId, ShardManager};
 
 use tracing_subscriber::{
     prelude::*,
@@ -19,22 +30,507 @@ use tracing_subscriber::{
     registry,
 };
 
-const OPENAI_API_KEY: &'static str = "";
-const OPENAI_ORG_ID: &'static str = "";
+pub struct Config {
+    /// Used round robin, skipping any that are rate limited or rejected for a while.
+    openai_api_keys: Vec<String>,
+    openai_org_id: Option<String>,
+    /// Where the OpenAI API is, without a trailing slash. Paths like `/v1/models` are appended to it.
+    /// For Azure, this is the deployment's URL, like
+    /// `https://<resource>.openai.azure.com/openai/deployments/<deployment>`.
+    openai_base_url: String,
+    /// Whether the API is Azure OpenAI, which takes the key in an `api-key` header and has no `/v1`
+    /// in its paths.
+    openai_azure: bool,
+    /// Sent as the `api-version` query parameter. Required by Azure.
+    openai_api_version: Option<String>,
+    /// Whether chat requests identify users to OpenAI by a hash of their Discord id, rather than the
+    /// id itself.
+    hash_user_ids: bool,
+
+    // Only needed for OAuth2 flows, which the bot doesn't do (yet).
+    #[allow(dead_code)]
+    discord_secret: Option<String>,
+    discord_token: String,
+
+    /// How many tokens of history (plus the new prompt) are sent to the model at most.
+    history_token_budget: usize,
+    /// Most turns remembered per conversation, counting prompts and replies separately. The oldest
+    /// are forgotten first.
+    max_history_turns: usize,
+    /// How long a chat history can go unused before it's dropped. Zero keeps them forever.
+    history_ttl: Duration,
+    /// Most messages walked up a chain of Discord replies to the bot, to rebuild the conversation
+    /// from it instead of the stored history. Zero disables this.
+    reply_chain_depth: usize,
+    /// Used when neither the request nor the guild specifies a model.
+    default_model: String,
+    /// Used when a request doesn't specify its own temperature.
+    default_temperature: f64,
+    /// Used when neither the request nor the conversation has its own system prompt.
+    default_system_prompt: Option<String>,
+    /// Language replies are asked to be in when a request doesn't give one, or `auto` for the
+    /// language of the prompt. If unset, the model picks.
+    default_reply_language: Option<String>,
+    /// Used when a request doesn't specify its own stop sequences.
+    default_stop: Vec<String>,
+
+    /// Most attempts made at an OpenAI request that keeps getting rate limited or erroring.
+    retry_max_attempts: u32,
+    /// Most time spent retrying an OpenAI request, including the time spent waiting between attempts.
+    retry_max_elapsed: Duration,
+    /// Failures in a row, each within `circuit_window` of the first, that stop OpenAI requests for
+    /// `circuit_cooldown`. Zero disables the circuit breaker.
+    circuit_failure_threshold: u32,
+    circuit_window: Duration,
+    /// How long OpenAI requests are refused once they've failed too often, before one is let through
+    /// to see whether OpenAI has recovered.
+    circuit_cooldown: Duration,
+    /// Most OpenAI requests in flight at once. Anything over this waits its turn.
+    max_concurrent_requests: usize,
+    /// Most time a request waits for its turn before giving up.
+    request_queue_timeout: Duration,
+    /// Most time a single OpenAI request may take, from sending it to reading the whole response.
+    request_timeout: Duration,
+    /// Most time spent establishing a connection to OpenAI.
+    connect_timeout: Duration,
+    /// How long the model list fetched from OpenAI is reused before fetching it again.
+    model_list_ttl: Duration,
+    /// The model to answer with instead, for each model that can have one, when it's still overloaded
+    /// after every retry. Only used by chats that ask for it.
+    fallback_models: HashMap<String, String>,
+
+    /// What classic commands start with, like the `-` in `-chat`.
+    command_prefix: String,
+    /// Longest prompt accepted, in characters. Zero allows any length.
+    max_prompt_chars: usize,
+    /// Whether classic commands stream the reply in, editing it as the model produces more text.
+    stream_responses: bool,
+    /// Whether classic commands, like `-chat`, are answered at all. With them off, the bot doesn't ask
+    /// for the privileged Message Content intent, so it doesn't need to be enabled for it.
+    classic_commands: bool,
+    /// Whether classic commands are reacted to while they're handled, and with how they turned out.
+    progress_reactions: bool,
+    /// Whether prompts are run through OpenAI's moderation endpoint before being sent to the model.
+    moderate_prompts: bool,
+    /// Most replies kept to answer identical requests with. Zero disables the cache.
+    response_cache_size: usize,
+    /// How long a cached reply can be reused for.
+    response_cache_ttl: Duration,
+    /// Whether chat requests are logged and answered with [`DRY_RUN_RESPONSE`] instead of being sent
+    /// to OpenAI. Moderation is skipped too.
+    dry_run: bool,
+    /// Largest audio attachment, in bytes, that will be transcribed.
+    max_audio_bytes: u64,
+    /// Most rounds of tool calls answered for a single prompt before giving up. Zero disables tools.
+    /// Tools are only offered to chat models, and not when the reply is streamed.
+    max_tool_rounds: usize,
+
+    /// Users allowed to use the bot. If this and `allowed_roles` are both empty, everyone is.
+    allowed_users: HashSet<UserId>,
+    /// Roles allowed to use the bot. If this and `allowed_users` are both empty, everyone is. DMs
+    /// have no roles, so this doesn't let anyone in there.
+    allowed_roles: HashSet<RoleId>,
+    /// Channels the bot answers in, counting threads in them. If empty, it answers everywhere. DMs
+    /// aren't channels anyone can list here, so this shuts the bot out of them.
+    allowed_channels: HashSet<ChannelId>,
+    /// Port the Prometheus metrics are served on. If unset, metrics aren't collected at all.
+    metrics_port: Option<u16>,
+    /// Port the dashboard of recent requests is served on. If unset, there's no dashboard.
+    dashboard_port: Option<u16>,
+    /// Bearer token the dashboard asks for. Required when `dashboard_port` is set.
+    dashboard_token: Option<String>,
+    /// How many of the latest chat requests the dashboard lists.
+    dashboard_requests: usize,
+    /// Guilds to register slash commands in, instead of globally. Guild commands show up right away,
+    /// which is handy while developing. If empty, commands are registered globally.
+    dev_guilds: Vec<GuildId>,
+
+    /// Users exempt from `cooldown` and `monthly_user_budget`.
+    admin_users: HashSet<UserId>,
+    /// Least time between a user's requests to OpenAI. Zero disables the cooldown.
+    cooldown: Duration,
+    /// Most estimated spend, in USD, on chat requests each calendar month (UTC). Zero disables the cap.
+    monthly_budget: f64,
+    /// Like `monthly_budget`, but for each user.
+    monthly_user_budget: f64,
+    /// Where the month's spend is kept, so that the caps hold across restarts. Only used when a cap
+    /// is set.
+    spend_file: String,
+    /// Where the guilds' configurations are kept, so that they hold across restarts.
+    guild_config_file: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            openai_api_keys: match list_env("OPENAI_API_KEYS") {
+                keys if keys.is_empty() => vec![required_env("OPENAI_API_KEY")],
+                keys => keys,
+            },
+            openai_org_id: optional_env("OPENAI_ORG_ID"),
+            openai_base_url: base_url_env("OPENAI_BASE_URL", "https://api.openai.com"),
+            openai_azure: parsed_env("OPENAI_AZURE", false),
+            openai_api_version: optional_env("OPENAI_API_VERSION"),
+            hash_user_ids: match optional_env("OPENAI_USER_ID").as_deref() {
+                None | Some("hashed") => true,
+                Some("raw") => false,
+                Some(mode) => panic!("environment variable `OPENAI_USER_ID` should be `hashed` or `raw`, but is `{mode}`"),
+            },
+
+            discord_secret: optional_env("DISCORD_SECRET"),
+            discord_token: required_env("DISCORD_TOKEN"),
+
+            history_token_budget: parsed_env("HISTORY_TOKEN_BUDGET", 2000),
+            max_history_turns: parsed_env("MAX_HISTORY_TURNS", 100),
+            history_ttl: Duration::from_secs(parsed_env("HISTORY_TTL_SECS", 7 * 24 * 60 * 60)),
+            reply_chain_depth: parsed_env("REPLY_CHAIN_DEPTH", 10),
+            default_model: known_model_env("DEFAULT_MODEL", "gpt-3.5-turbo"),
+            default_temperature: parsed_env("DEFAULT_TEMPERATURE", 1.0),
+            default_system_prompt: optional_env("DEFAULT_SYSTEM_PROMPT"),
+            default_reply_language: optional_env("REPLY_LANGUAGE").map(|language| validate_language(language.as_str()).unwrap_or_else(|e| panic!("environment variable `REPLY_LANGUAGE` is invalid: {e}")).to_owned()),
+            default_stop: validate_stop(list_env("DEFAULT_STOP_SEQUENCES")).unwrap_or_else(|e| panic!("environment variable `DEFAULT_STOP_SEQUENCES` is invalid: {e}")),
+
+            retry_max_attempts: parsed_env("OPENAI_RETRY_MAX_ATTEMPTS", 5),
+            retry_max_elapsed: Duration::from_secs(parsed_env("OPENAI_RETRY_MAX_SECS", 60)),
+            circuit_failure_threshold: parsed_env("OPENAI_CIRCUIT_FAILURES", 5),
+            circuit_window: Duration::from_secs(parsed_env("OPENAI_CIRCUIT_WINDOW_SECS", 60)),
+            circuit_cooldown: Duration::from_secs(parsed_env("OPENAI_CIRCUIT_COOLDOWN_SECS", 30)),
+            max_concurrent_requests: parsed_env("OPENAI_MAX_CONCURRENT_REQUESTS", 4),
+            request_queue_timeout: Duration::from_secs(parsed_env("OPENAI_QUEUE_TIMEOUT_SECS", 30)),
+            request_timeout: Duration::from_secs(parsed_env("OPENAI_REQUEST_TIMEOUT_SECS", 120)),
+            connect_timeout: Duration::from_secs(parsed_env("OPENAI_CONNECT_TIMEOUT_SECS", 10)),
+            model_list_ttl: Duration::from_secs(parsed_env("OPENAI_MODEL_LIST_TTL_SECS", 3600)),
+            fallback_models: fallback_models_env("FALLBACK_MODELS"),
+
+            command_prefix: optional_env("COMMAND_PREFIX").unwrap_or_else(|| "-".to_owned()),
+            max_prompt_chars: parsed_env("MAX_PROMPT_CHARS", 4000),
+            stream_responses: parsed_env("STREAM_RESPONSES", false),
+            classic_commands: parsed_env("CLASSIC_COMMANDS", true),
+            progress_reactions: parsed_env("PROGRESS_REACTIONS", false),
+            moderate_prompts: parsed_env("MODERATE_PROMPTS", false),
+            response_cache_size: parsed_env("RESPONSE_CACHE_SIZE", 0),
+            response_cache_ttl: Duration::from_secs(parsed_env("RESPONSE_CACHE_TTL_SECS", 3600)),
+            dry_run: parsed_env("DRY_RUN", false),
+            max_audio_bytes: parsed_env("MAX_AUDIO_BYTES", 25 * 1024 * 1024),
+            max_tool_rounds: parsed_env("MAX_TOOL_ROUNDS", 5),
+
+            allowed_users: list_env("ALLOWED_USERS").into_iter().map(UserId).collect(),
+            allowed_roles: list_env("ALLOWED_ROLES").into_iter().map(RoleId).collect(),
+            allowed_channels: list_env("ALLOWED_CHANNELS").into_iter().map(ChannelId).collect(),
+            dashboard_port: optional_env("DASHBOARD_PORT").map(|port| port.parse().unwrap_or_else(|e| panic!("environment variable `DASHBOARD_PORT` could not be parsed from `{port}`: {e}"))),
+            dashboard_token: optional_env("DASHBOARD_TOKEN"),
+            dashboard_requests: parsed_env("DASHBOARD_REQUESTS", 50),
+            metrics_port: optional_env("METRICS_PORT").map(|port| port.parse().unwrap_or_else(|e| panic!("environment variable `METRICS_PORT` could not be parsed from `{port}`: {e}"))),
+            dev_guilds: list_env("DEV_GUILDS").into_iter().map(GuildId).collect(),
+
+            admin_users: list_env("ADMIN_USERS").into_iter().map(UserId).collect(),
+            cooldown: Duration::from_secs(parsed_env("COOLDOWN_SECS", 0)),
+            monthly_budget: parsed_env("MONTHLY_BUDGET_USD", 0.0),
+            monthly_user_budget: parsed_env("MONTHLY_USER_BUDGET_USD", 0.0),
+            spend_file: parsed_env("SPEND_FILE", "spend.json".to_owned()),
+            guild_config_file: parsed_env("GUILD_CONFIG_FILE", "guild_configs.json".to_owned()),
+        }.validate()
+    }
+
+    /// Panics if settings that are fine on their own don't work together.
+    fn validate(self) -> Self {
+        if self.openai_azure && self.openai_api_version.is_none() {
+            panic!("environment variable `OPENAI_API_VERSION` is required when `OPENAI_AZURE` is set");
+        }
+        self
+    }
+
+    /// Whether the bot answers in the channel with `channel_id`. For threads, this should be the
+    /// parent channel.
+    fn is_channel_allowed(&self, channel_id: ChannelId) -> bool {
+        self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel_id)
+    }
+
+    /// Whether someone with `user_id` and, in guilds, `roles` may use the bot.
+    fn is_allowed(&self, user_id: UserId, roles: Option<&[RoleId]>) -> bool {
+        if self.allowed_users.is_empty() && self.allowed_roles.is_empty() {
+            return true;
+        }
+        self.allowed_users.contains(&user_id)
+            || roles.unwrap_or(&[]).iter().any(|role| self.allowed_roles.contains(role))
+    }
+}
+
+fn required_env(name: &str) -> String {
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => value,
+        Ok(_) => panic!("environment variable `{name}` is required, but is empty"),
+        Err(std::env::VarError::NotPresent) => panic!("environment variable `{name}` is required, but is not set"),
+        Err(std::env::VarError::NotUnicode(_)) => panic!("environment variable `{name}` is required, but is not valid unicode"),
+    }
+}
+
+fn optional_env(name: &str) -> Option<String> {
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => Some(value),
+        Ok(_) | Err(std::env::VarError::NotPresent) => None,
+        Err(std::env::VarError::NotUnicode(_)) => panic!("environment variable `{name}` is not valid unicode"),
+    }
+}
+
+fn parsed_env<T: std::str::FromStr>(name: &str, default: T) -> T where T::Err: std::fmt::Display {
+    match optional_env(name) {
+        Some(value) => value.parse().unwrap_or_else(|e| panic!("environment variable `{name}` could not be parsed from `{value}`: {e}")),
+        None => default,
+    }
+}
+
+fn known_model_env(name: &str, default: &str) -> String {
+    let model = optional_env(name).unwrap_or_else(|| default.to_owned());
+    if !is_known_model(model.as_str()) {
+        panic!("environment variable `{name}` should be one of {}, but is `{model}`", model_list());
+    }
+    model
+}
+
+/// Parses a comma separated list of `model=fallback` pairs of known models.
+fn fallback_models_env(name: &str) -> HashMap<String, String> {
+    list_env::<String>(name).into_iter().map(|pair| {
+        let Some((model, fallback)) = pair.split_once('=') else {
+            panic!("environment variable `{name}` should be a list of `model=fallback` pairs, but has `{pair}`");
+        };
+        let (model, fallback) = (model.trim(), fallback.trim());
+        if let Some(unknown) = [model, fallback].into_iter().find(|model| !is_known_model(model)) {
+            panic!("environment variable `{name}` should only name models out of {}, but has `{unknown}`", model_list());
+        }
+        if model == fallback {
+            panic!("environment variable `{name}` falls back from `{model}` to itself");
+        }
+        (model.to_owned(), fallback.to_owned())
+    }).collect()
+}
+
+/// An http(s) URL, without a trailing slash.
+fn base_url_env(name: &str, default: &str) -> String {
+    let url = optional_env(name).unwrap_or_else(|| default.to_owned());
+    match reqwest::Url::parse(url.as_str()) {
+        Ok(parsed) if ["http", "https"].contains(&parsed.scheme()) && parsed.query().is_none() => url.trim_end_matches('/').to_owned(),
+        Ok(_) => panic!("environment variable `{name}` should be an http or https URL without a query, but is `{url}`"),
+        Err(e) => panic!("environment variable `{name}` could not be parsed as a URL from `{url}`: {e}"),
+    }
+}
+
+/// Parses a comma separated list, ignoring empty entries.
+fn list_env<T: std::str::FromStr>(name: &str) -> Vec<T> where T::Err: std::fmt::Display {
+    let Some(value) = optional_env(name) else {
+        return vec![];
+    };
+    value.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| entry.parse().unwrap_or_else(|e| panic!("environment variable `{name}` has an entry that could not be parsed from `{entry}`: {e}")))
+        .collect()
+}
+
+/// Everything that can go wrong while handling a command.
+#[derive(Debug, thiserror::Error)]
+enum BotError {
+    /// OpenAI couldn't be reached, or didn't give back something usable. The message is shown to
+    /// the user.
+    #[error("{0}")]
+    OpenAi(String),
+    /// OpenAI was still overloaded or rate limiting after every retry. The message is shown to the
+    /// user.
+    #[error("{0}")]
+    Overloaded(String),
+    /// Talking to Discord failed. The details are only logged.
+    #[error("Discord request failed: {0}")]
+    Discord(String),
+    /// The user asked for something that can't be done. The message is shown to the user.
+    #[error("{0}")]
+    UserInput(String),
+    /// Something that should never happen happened. The details are only logged, at the site.
+    #[error("internal error")]
+    Internal,
+}
+
+impl BotError {
+    /// What to tell the user about this error. Only the generic message is translated into `locale`.
+    fn user_message(&self, locale: Locale) -> &str {
+        match self {
+            Self::OpenAi(message) | Self::Overloaded(message) | Self::UserInput(message) => message.as_str(),
+            Self::Discord(_) | Self::Internal => Text::ErrorOccurred.get(locale),
+        }
+    }
+
+    fn is_user_error(&self) -> bool {
+        matches!(self, Self::UserInput(_))
+    }
+}
+
+impl From<serenity::Error> for BotError {
+    fn from(e: serenity::Error) -> Self {
+        Self::Discord(e.to_string())
+    }
+}
+
+/// Discord's error codes for a webhook token that's no longer valid.
+const UNKNOWN_WEBHOOK: isize = 10015;
+const INVALID_WEBHOOK_TOKEN: isize = 50027;
+
+/// Whether Discord refused a followup because the interaction token expired, which happens about 15
+/// minutes after the interaction.
+fn is_expired_interaction(e: &serenity::Error) -> bool {
+    match e {
+        serenity::Error::Http(e) => match e.as_ref() {
+            serenity::http::HttpError::UnsuccessfulRequest(response) => matches!(response.error.code, UNKNOWN_WEBHOOK | INVALID_WEBHOOK_TOKEN),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Languages the bot's own messages are available in. Whatever OpenAI says, and the errors it
+/// returns, are passed along as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Locale {
+    #[default]
+    English,
+    Spanish,
+}
+
+impl Locale {
+    /// Picks the language for a Discord locale like `es-ES`, falling back to English.
+    fn from_discord(locale: &str) -> Self {
+        match locale.split('-').next() {
+            Some("es") => Self::Spanish,
+            _ => Self::English,
+        }
+    }
+
+    /// Classic commands don't come with the user's locale, so the guild's preferred one is used. DMs
+    /// fall back to English.
+    fn of_guild(ctx: &Context, guild_id: Option<GuildId>) -> Self {
+        guild_id
+            .and_then(|guild_id| ctx.cache.guild_field(guild_id, |guild| Self::from_discord(guild.preferred_locale.as_str())))
+            .unwrap_or_default()
+    }
+}
+
+/// Messages the bot sends that don't depend on anything but the language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Text {
+    ErrorOccurred,
+    NotAuthorized,
+    NotEnabledHere,
+    Thinking,
+    ClearConfirmation,
+    ConfirmClearButton,
+    CancelClearButton,
+    ClearExpired,
+    HistoryCleared,
+    HistoryClearedEverywhere,
+    HistoryKept,
+    ResetUser,
+    ResetGuild,
+    NothingToExport,
+    NothingRemembered,
+    NothingToUndo,
+    UndidExchange,
+    UndidSummary,
+    RegenerateButton,
+    ClearHistoryButton,
+    ContinueButton,
+    EditResendButton,
+    ResendExpired,
+    StopButton,
+    ReplyExpired,
+    ReplyTruncated,
+    StillWorking,
+    FallbackUsed,
+    PickReply,
+    PickReplyPlaceholder,
+}
 
-const DISCORD_SECRET: &'static str = "";
-const DISCORD_TOKEN: &'static str = "";
+impl Text {
+    fn get(self, locale: Locale) -> &'static str {
+        let (english, spanish) = match self {
+            Self::ErrorOccurred => ("An error occurred", "Ocurrió un error"),
+            Self::NotAuthorized => ("Sorry, you're not authorized to use this bot.", "Lo siento, no tienes permiso para usar este bot."),
+            Self::NotEnabledHere => ("I'm not enabled in this channel.", "No estoy activado en este canal."),
+            Self::Thinking => ("Thinking...", "Pensando..."),
+            Self::ClearConfirmation => ("Clear your chat history here? This can't be undone.", "¿Borrar tu historial de chat aquí? No se puede deshacer."),
+            Self::ConfirmClearButton => ("Yes, clear it", "Sí, bórralo"),
+            Self::CancelClearButton => ("No", "No"),
+            Self::ClearExpired => (
+                "This expired before it was answered, so nothing was cleared. Ask to clear again if you still want to.",
+                "Esto caducó antes de que respondieras, así que no se borró nada. Vuelve a pedirlo si aún quieres borrarlo.",
+            ),
+            Self::HistoryCleared => ("Chat history cleared.", "Historial de chat borrado."),
+            Self::HistoryClearedEverywhere => ("Chat history cleared in every channel.", "Historial de chat borrado en todos los canales."),
+            Self::HistoryKept => ("Kept your chat history.", "Se conservó tu historial de chat."),
+            Self::ResetUser => ("Cleared your chat history and system prompts in every channel.", "Se borraron tu historial de chat y tus instrucciones de sistema en todos los canales."),
+            Self::ResetGuild => (
+                "Cleared everyone's chat history in this server and put its defaults back.",
+                "Se borró el historial de chat de todos en este servidor y se restauraron sus valores predeterminados.",
+            ),
+            Self::NothingToExport => ("There's nothing to export yet. Send a prompt first.", "Todavía no hay nada que exportar. Envía un mensaje primero."),
+            Self::NothingRemembered => ("I don't remember anything from this conversation yet.", "Todavía no recuerdo nada de esta conversación."),
+            Self::NothingToUndo => ("There's nothing to undo.", "No hay nada que deshacer."),
+            Self::UndidExchange => ("Removed your last exchange.", "Se eliminó tu último intercambio."),
+            Self::UndidSummary => ("Brought back the chat history from before it was summarized.", "Se recuperó el historial de chat de antes del resumen."),
+            Self::RegenerateButton => ("Regenerate", "Regenerar"),
+            Self::ClearHistoryButton => ("Clear history", "Borrar historial"),
+            Self::ContinueButton => ("Continue", "Continuar"),
+            Self::EditResendButton => ("Edit & Resend", "Editar y reenviar"),
+            Self::ResendExpired => ("Only whoever sent this prompt can resend it, and only for a while after it failed.", "Solo quien envió este mensaje puede reenviarlo, y solo durante un tiempo después del fallo."),
+            Self::StopButton => ("Stop", "Detener"),
+            Self::StillWorking => ("Still working (retrying, attempt {attempt})...", "Sigo trabajando (reintentando, intento {attempt})..."),
+            Self::FallbackUsed => ("Answered by {fallback}, since {model} was busy.", "Respondido por {fallback}, ya que {model} estaba ocupado."),
+            Self::ReplyTruncated => ("[message truncated due to a Discord error]", "[mensaje truncado por un error de Discord]"),
+            Self::ReplyExpired => ("This reply expired, so the rest of it can't be shown anymore.", "Esta respuesta caducó, así que ya no se puede mostrar el resto."),
+            Self::PickReply => ("Pick the reply to keep:", "Elige la respuesta que quieres conservar:"),
+            Self::PickReplyPlaceholder => ("Keep a reply", "Conservar una respuesta"),
+        };
+        match locale {
+            Locale::English => english,
+            Locale::Spanish => spanish,
+        }
+    }
+}
 
+/// Which logs are kept. In order of precedence:
+///
+/// 1. `directives`, if set, is used as is, like `RUST_LOG` usually is.
+/// 2. Otherwise, `level` applies to everything, and is followed by `filter`. Since later directives
+///    win, `filter` can quiet (or raise) specific targets.
+///
+/// Leaving `filter` unset quiets a handful of noisy dependencies. Setting it to an empty string
+/// doesn't quiet anything.
 pub struct LoggingCfg {
+    directives: Option<String>,
     level: String,
     filter: Option<String>,
+    /// Whether each log is written as a JSON object, rather than as human readable text.
+    json: bool,
+}
+
+impl LoggingCfg {
+    /// Reads `RUST_LOG`, `LOG_LEVEL` (defaulting to `info`), `LOG_FILTER`, and `LOG_FORMAT` (`text`
+    /// or `json`, defaulting to `text`).
+    pub fn from_env() -> Self {
+        Self {
+            directives: optional_env("RUST_LOG"),
+            level: optional_env("LOG_LEVEL").unwrap_or_else(|| "info".to_owned()),
+            // Unlike other settings, an empty filter means something.
+            filter: std::env::var("LOG_FILTER").ok(),
+            json: match optional_env("LOG_FORMAT").as_deref() {
+                None | Some("text") => false,
+                Some("json") => true,
+                Some(format) => panic!("environment variable `LOG_FORMAT` should be `text` or `json`, but is `{format}`"),
+            },
+        }
+    }
 }
 
 pub fn setup_logging(cfg: LoggingCfg) {
-    // This should really go in the environment, but should suffice. If it gets any more complicated,
-    // we'll use the environment.
-    // const LOGGING_FILTER: &'static str = "tracing::span=warn,rustls=warn,h2=warn,tungstenite=warn,hyper=warn,reqwest=warn,serenity=warn";
-    const LOGGING_FILTER: &'static str = "rustls=warn,h2=warn,tungstenite=warn,hyper=warn,reqwest=warn,serenity=warn";
+    // const LOGGING_FILTER: &str = "tracing::span=warn,rustls=warn,h2=warn,tungstenite=warn,hyper=warn,reqwest=warn,serenity=warn";
+    const LOGGING_FILTER: &str = "rustls=warn,h2=warn,tungstenite=warn,hyper=warn,reqwest=warn,serenity=warn";
 
     let level = cfg.level.as_str();
     let filter: Cow<_> = if let Some(filter) = cfg.filter {
@@ -42,336 +538,5674 @@ pub fn setup_logging(cfg: LoggingCfg) {
     } else {
         LOGGING_FILTER.into()
     };
-    let filter: Cow<_> = if filter.is_empty() {
+    let filter: Cow<_> = if let Some(directives) = cfg.directives {
+        directives.into()
+    } else if filter.is_empty() {
         level.into()
     } else {
         format!("{level},{filter}").into()
     };
-    println!("Logging is being initialized with {filter}.");
+    let format = if cfg.json { "json" } else { "text" };
+    println!("Logging is being initialized with {filter}, as {format}.");
     let filter = EnvFilter::builder()
         .parse(filter.as_ref())
-        .expect("logging.level to be a valid log level/logging.filter to be a valid filter");
+        .expect("RUST_LOG to be a valid filter, or LOG_LEVEL to be a valid log level and LOG_FILTER to be a valid filter");
 
-    let logger = fmt::layer();
+    // Only one of these is ever present.
+    let (text_logger, json_logger) = if cfg.json {
+        (None, Some(fmt::layer().json()))
+    } else {
+        (Some(fmt::layer()), None)
+    };
 
     registry()
         .with(filter)
-        .with(logger)
+        .with(text_logger)
+        .with(json_logger)
         .init();
 
-    log::info!("Logging initialized successfully.");
+    log::info!("Logging initialized successfully, as {format}.");
 }
 
-async fn build_client() -> serenity::Result<Client> {
-    // Login with a bot token from the environment
-    let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
-    Client::builder(DISCORD_TOKEN, intents)
-        .event_handler(Handler {
-            chat_histories: Mutex::new(HashMap::new()),
-        })
+async fn build_client(discord_token: &str, cfg: Arc<Config>, metrics: Option<Arc<Mutex<Metrics>>>, recent_requests: Option<Arc<RecentRequests>>) -> serenity::Result<Client> {
+    // `non_privileged` already covers DMs, but they're spelled out since the bot relies on them.
+    let mut intents = GatewayIntents::non_privileged() | GatewayIntents::DIRECT_MESSAGES;
+    // Only classic commands need to read messages.
+    if cfg.classic_commands {
+        intents |= GatewayIntents::MESSAGE_CONTENT;
+    }
+    Client::builder(discord_token, intents)
+        .event_handler(Handler::new(cfg, metrics, recent_requests))
         .await
 }
 
-struct Handler {
-    chat_histories: Mutex<HashMap<UserId, Arc<Mutex<String>>>>,
+/// Identifies a single conversation: one user, in one channel or thread. DMs don't have a guild.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct HistoryKey {
+    guild_id: Option<GuildId>,
+    /// For threads, this is the parent channel.
+    channel_id: ChannelId,
+    thread_id: Option<ChannelId>,
+    user_id: UserId,
 }
 
-fn build_openai_client() -> Result<reqwest::Client, ()> {
-    let mut default_client_headers = HeaderMap::new();
-    // Bearer Auth
-    default_client_headers.insert("Authorization", format!("Bearer {OPENAI_API_KEY}").try_into().expect("API key header is valid"));
-
-    let res = reqwest::Client::builder()
-        .default_headers(default_client_headers)
-        .build();
+impl HistoryKey {
+    /// Builds the key for a conversation happening in `channel_id`, which may be a thread.
+    async fn resolve(ctx: &Context, guild_id: Option<GuildId>, channel_id: ChannelId, user_id: UserId) -> Self {
+        let parent_id = match channel_id.to_channel(ctx).await {
+            Ok(Channel::Guild(channel)) if is_thread(channel.kind) => channel.parent_id,
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("Failed to look up channel {channel_id:?}. Assuming it isn't a thread. Error: {e:?}");
+                None
+            },
+        };
+        match parent_id {
+            Some(parent_id) => Self { guild_id, channel_id: parent_id, thread_id: Some(channel_id), user_id },
+            None => Self { guild_id, channel_id, thread_id: None, user_id },
+        }
+    }
 
-    res.ok().ok_or(())
+    /// The key for the same user's conversation in `thread_id`, a thread in this key's channel.
+    fn in_thread(self, thread_id: ChannelId) -> Self {
+        Self { thread_id: Some(thread_id), ..self }
+    }
 }
 
-fn build_completion(prompt: &str) -> serde_json::Value {
-    serde_json::json!({
-        "model": "text-davinci-003",
-        "prompt": prompt,
-        "max_tokens": 500,
-        "suffix": null,
-        "n": 1,
-    })
+fn is_thread(kind: ChannelType) -> bool {
+    matches!(kind, ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread)
 }
 
-impl Handler {
-    fn show_time<TZ: chrono::TimeZone>(ui: &str, source: &str, data: impl std::fmt::Display, start: chrono::DateTime<TZ>, end: chrono::DateTime<TZ>) {
-        let diff = end - start;
-        let diff_ns = diff.num_nanoseconds().unwrap_or(-1);
-        if diff <= chrono::Duration::seconds(1) {
-            let diff_human = diff.num_milliseconds();
-            log::info!("TIMING ui={ui} {source}={data} duration={diff_ns}ns human={diff_human}ms");
-        } else {
-            let diff_human = "parsing_todo"; // TODO
-            log::info!("TIMING ui={ui} {source}={data} duration={diff_ns}ns human={diff_human}");
-        }
-    }
+/// Splits a classic command, like `-chat gpt-4 hi`, into its name and the rest of the message, if
+/// `content` starts with `prefix`.
+fn split_classic_command<'a>(content: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    let content = content.strip_prefix(prefix)?;
+    Some(content.split_once(|c: char| c.is_whitespace()).unwrap_or((content, "")))
+}
 
-    async fn chat(&self, user_id: UserId, user_name: &str, model: &str, prompt: &str) -> Result<String, Option<Cow<'static, str>>> {
-        log::info!("COMMAND-PARSED model={model:?}, prompt={prompt:?}");
+/// Describes every command, with classic commands starting with `prefix`. Without a prefix, classic
+/// commands are left out, since they're turned off.
+fn help_text(prefix: Option<&str>) -> String {
+    let models = model_list();
+    let classic = match prefix {
+        Some(prefix) => format!("\n\
+**Classic commands**
+`{prefix}chat [model] [max_tokens=<n>] [top_p=<p>] [seed=<n>] [language=<name|auto>] [format=<plain|embed|code>] [json=<true|false>] [fallback=<true|false>] [thread=<true|false>] [stop=<a,b>] <prompt>`: Chat with an AI model. Reply to a message to include it as context. Attach audio instead of writing a prompt to speak it, or attach images for vision models to see.
+`{prefix}transcribe`: Transcribe an attached audio file.
+`{prefix}regenerate`: Get a different answer to your last prompt.
+`{prefix}setmodel <model>`: Use a model for the rest of this conversation, whenever a chat doesn't name one.
+`{prefix}persona <prompt|reset>`: Change the system prompt for the rest of this conversation, without clearing it. Use `reset` to go back to the default.
+`{prefix}undo`: Remove your last prompt and its reply from the chat history, or undo a summary.
+`{prefix}summarize`: Replace your chat history here with a summary of it, to use fewer tokens.
+`{prefix}export`: Download your chat history here as a Markdown file.
+`{prefix}context`: Show what the bot remembers of your chat here, and about how many tokens it is.
+`{prefix}pins`: Send the pinned messages most relevant to your next prompt along with it.
+`{prefix}clear`: Clear your chat history here, after confirming.
+`{prefix}clear-force`: Clear your chat history here without confirming.
+`{prefix}clear-all`: Clear your chat history everywhere.
+`{prefix}stop_generation`: Refuse every new request and stop the replies in progress. Bot admins only.
+`{prefix}resume`: Answer requests again after `{prefix}stop_generation`. Bot admins only.
+`{prefix}help`: Show this message.
+"),
+        None => String::new(),
+    };
+    format!("\
+**Slash commands**
+`/chat prompt [model] [temperature] [top_p] [max_tokens] [presence_penalty] [frequency_penalty] [image] [choices] [seed] [language] [format] [json] [fallback] [logprobs] [stop] [system] [private] [thread]`: Chat with an AI model. With `choices`, pick which reply to keep.
+`/regenerate`: Get a different answer to your last prompt.
+`/compose [model]`: Write a long prompt, with line breaks, in a text box.
+`/setmodel model`: Use a model for the rest of this conversation, whenever a chat doesn't name one.
+`/persona prompt`: Change the system prompt for the rest of this conversation, without clearing it. Use `reset` to go back to the default.
+`/undo`: Remove your last prompt and its reply from the chat history, or undo a summary.
+`/summarize`: Replace your chat history here with a summary of it, to use fewer tokens.
+`/export`: Download your chat history here as a Markdown file.
+`/context`: Show what the bot remembers of your chat here, and about how many tokens it is.
+`/pins`: Send the pinned messages most relevant to your next prompt along with it.
+`/clear`: Clear your chat history here, after confirming.
+`/reset [scope]`: Clear your chat history and system prompts everywhere. Server managers can use `scope:server` to clear everyone's history in this server and put its defaults back.
+`/image prompt [size]`: Generate an image with DALL-E.
+`/models`: List the chat models OpenAI currently offers.
+`/usage`: Show your token usage and estimated cost.
+`/config [model] [temperature] [system] [template] [format] [reset]`: Set this server's defaults. Server managers only.
+`/ping`: Check whether the bot and OpenAI are responding.
+`/stop_generation`: Refuse every new request and stop the replies in progress. Bot admins only.
+`/resume`: Answer requests again after `/stop_generation`. Bot admins only.
+`/help`: Show this message.
+{classic}
+**Models**
+{models}")
+}
 
-        let history = {
-            let mut history = self.chat_histories.lock();
-            Arc::clone(history.entry(user_id).or_default())
-        };
+/// Splits a leading model off of `args`, but only if it's one the bot knows. Otherwise, all of `args`
+/// is left as the rest.
+fn split_classic_model(args: &str) -> (Option<&str>, &str) {
+    let (first, rest) = args.split_once(|c: char| c.is_whitespace()).unwrap_or((args, ""));
+    if is_known_model(first) {
+        (Some(first), rest)
+    } else {
+        (None, args)
+    }
+}
 
-        let client = build_openai_client().map_err(|e| {
-            log::warn!("OpenAI client build failed. Error: {e:?}");
-            None
-        })?;
+/// Splits a leading `name=value` option off of `text`, returning the name, value, and the rest.
+fn split_classic_option(text: &str) -> Option<(&str, &str, &str)> {
+    let (option, rest) = text.split_once(|c: char| c.is_whitespace()).unwrap_or((text, ""));
+    let (name, value) = option.split_once('=')?;
+    Some((name, value, rest.trim_start()))
+}
 
-        const MAX_PROMPT_LEN: usize = 2000;
+/// Most characters of a replied-to message included as context.
+const REFERENCED_MESSAGE_LIMIT: usize = 1000;
 
-        let locked_history = history.lock().clone();
-        let relevant_history_with_prompt = {
-            let saved_history = locked_history.as_str();
-            let history_and_prompt = format!("{saved_history}\n\nPrompt from {user_name}: {prompt}");
-            let slice_prompt_start = if history_and_prompt.len() > MAX_PROMPT_LEN {
-                history_and_prompt.len() - MAX_PROMPT_LEN
-            } else {
-                0
-            };
-            history_and_prompt[slice_prompt_start..].to_owned()
-        };
+/// Discord limits thread names to this many characters.
+const THREAD_NAME_LIMIT: usize = 100;
 
-        let request_body = build_completion(relevant_history_with_prompt.as_str());
-        let response = match client.post("https://api.openai.com/v1/completions").json(&request_body).send().await {
-            Ok(response) => response,
-            Err(e) => {
-                log::error!("Completion post failed due to {e:?}");
-                return Err(None);
-            },
-        };
+fn thread_name(prompt: &str) -> String {
+    prompt.chars().take(THREAD_NAME_LIMIT).collect()
+}
 
-        let outcome: serde_json::Value = match response.json().await {
-            Ok(value) => value,
-            Err(e) => {
-                log::error!("Completion post failed getting body due to {e:?}");
-                return Err(None);
-            },
-        };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    User,
+    Assistant,
+    /// Context the bot added itself, like a summary of earlier turns.
+    System,
+}
 
-        log::info!("post replied with {outcome:?}");
-        let choice_0_text = outcome
-            .as_object().expect("an object")
-            .get("choices").expect("choices to be present")
-            .as_array().expect("an array")
-            .get(0).expect("choice to be present")
-            .as_object().expect("an object")
-            .get("text").expect("text to be present")
-            .as_str().expect("a string");
+impl Role {
+    /// The role's name in chat completion messages.
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Assistant => "assistant",
+            Self::System => "system",
+        }
+    }
+}
 
-        history.lock().push_str(format!("\n\n{user_name}: {prompt}\n{model}: {choice_0_text}").as_str());
+/// A single message in a conversation.
+#[derive(Debug, Clone)]
+struct ChatTurn {
+    role: Role,
+    /// Who sent it: the user's name, or the model's identifier.
+    name: String,
+    content: String,
+    /// When it was added to the history.
+    at: chrono::DateTime<chrono::Utc>,
+}
 
-        Ok(choice_0_text.to_owned())
+impl ChatTurn {
+    fn count_tokens(&self) -> usize {
+        count_tokens(self.name.as_str()) + count_tokens(self.content.as_str())
     }
+}
 
-    async fn clear(&self, user_id: UserId) -> Result<(), Option<Cow<'static, str>>> {
-        self.chat_histories.lock().remove(&user_id);
+/// Renders `turns` as a plain text transcript, for models without a chat endpoint.
+fn render_transcript(turns: &[ChatTurn]) -> String {
+    turns.iter()
+        .map(|turn| match turn.role {
+            Role::User => format!("\n\n{}: {}", turn.name, turn.content),
+            Role::Assistant => format!("\n{}: {}", turn.name, turn.content),
+            Role::System => format!("\n\n{}", turn.content),
+        })
+        .collect()
+}
 
-        Ok(())
-    }
+const EXPORT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
 
-    async fn handle_autocomp_and_errors(&self, ctx: Context, autocomplete: AutocompleteInteraction) {
-        log::info!("BEGIN ui=discord_autocomp interaction={autocomplete:?}");
-        let id = autocomplete.id;
-        let res = autocomplete.create_autocomplete_response(&ctx, |response| {
-            response.set_choices(serde_json::json!({}))
-        }).await;
-        match res {
-            Ok(_) => {
-                log::info!("COMPLETE ui=discord_autocomp interaction={id:?}")
-            },
-            Err(e) => {
-                log::error!("COMPLETE ui=discord_autocomp interaction={id:?} error={e:?} user_error=false")
-            },
-        }
+/// Renders a conversation as a Markdown document, for users to keep.
+fn render_export(system_prompt: Option<&str>, turns: &[ChatTurn]) -> String {
+    let mut export = format!("# Chat transcript\n\nExported {}\n", chrono::Utc::now().format(EXPORT_TIME_FORMAT));
+    if let Some(system_prompt) = system_prompt {
+        export.push_str(format!("\n## System prompt\n\n{system_prompt}\n").as_str());
     }
+    for turn in turns {
+        export.push_str(format!("\n## {} ({}), {}\n\n{}\n", turn.name, turn.role.as_str(), turn.at.format(EXPORT_TIME_FORMAT), turn.content.trim()).as_str());
+    }
+    export
+}
 
-    async fn handle_msgcomp_and_errors(&self, ctx: Context, msgcomponent: MessageComponentInteraction) {
-        msgcomponent.defer(&ctx).await;
+/// What the bot remembers of a conversation, sized to fit in an embed.
+struct ContextView {
+    /// The newest turns that fit, oldest first, after a note about any that didn't.
+    description: String,
+    turns: usize,
+    /// Estimated with [`count_tokens`], counting the system prompt.
+    tokens: usize,
+}
+
+/// Most characters of a single turn shown by [`render_context`].
+const CONTEXT_TURN_CHARS: usize = 500;
+
+fn render_context(system_prompt: Option<&str>, turns: &[ChatTurn]) -> ContextView {
+    let tokens = system_prompt.map_or(0, count_tokens) + turns.iter().map(ChatTurn::count_tokens).sum::<usize>();
+    let mut header = String::new();
+    if let Some(system_prompt) = system_prompt {
+        header.push_str(format!("**System prompt**: {}\n\n", truncate_chars(system_prompt.trim(), CONTEXT_TURN_CHARS)).as_str());
     }
+    // Room for the note about turns that didn't fit.
+    let budget = MAX_EMBED_DESCRIPTION_CHARS.saturating_sub(header.chars().count() + 50);
 
-    async fn handle_appcomm_and_errors(&self, ctx: Context, appcommand: ApplicationCommandInteraction) {
-        log::info!("BEGIN ui=discord_appcomm interaction={appcommand:?}");
-        let interaction_id = appcommand.id;
-        match self.handle_appcomm(&ctx, &appcommand).await {
-            Ok(_) => {
-                log::info!("COMPLETE ui=discord_appcomm message={interaction_id:?} outcome=success");
-            },
-            Err(e0) => {
-                let message = e0.as_ref().map(|e| e.as_ref()).unwrap_or("An error occurred");
-                match appcommand.create_followup_message(ctx, |m| m.content(message)).await {
-                    Ok(_) => {
-                        log::error!("COMPLETE ui=discord_appcomm interaction={interaction_id:?} outcome=error error={e0:?} user_error=false");
-                    },
-                    Err(e1) => {
-                        log::error!("COMPLETE ui=discord_appcomm interaction={interaction_id:?} outcome=error primary_error={e0:?} secondary_error={e1:?} user_error=false");
-                    },
-                }
-            },
+    let mut shown = Vec::new();
+    let mut used = 0;
+    for turn in turns.iter().rev() {
+        let line = format!("**{}** ({}): {}\n", turn.name, turn.role.as_str(), truncate_chars(turn.content.trim(), CONTEXT_TURN_CHARS));
+        let len = line.chars().count();
+        if used + len > budget {
+            break;
         }
+        used += len;
+        shown.push(line);
     }
 
-    async fn handle_appcomm(&self, ctx: &Context, appcommand: &ApplicationCommandInteraction) -> Result<(), Option<Cow<'static, str>>> {
-        if let Err(e) = appcommand.defer(&ctx).await {
-            log::error!("Application command failed to be deferred. Error: {e:?}");
-            return Err(None);
-        }
+    let mut description = header;
+    let hidden = turns.len() - shown.len();
+    if hidden > 0 {
+        description.push_str(format!("*{hidden} earlier turns not shown.*\n").as_str());
+    }
+    description.extend(shown.into_iter().rev());
+    ContextView { description, turns: turns.len(), tokens }
+}
 
-        if appcommand.data.name == "clear" {
-            self.clear(appcommand.user.id).await?;
-            appcommand.create_followup_message(ctx, |m| m.content("Chat history cleared.")).await.ok().ok_or(None)?;
-            return Ok(());
-        }
+#[derive(Debug, Clone, Default)]
+struct ChatHistory {
+    /// Oldest first.
+    turns: Vec<ChatTurn>,
+    /// Set by the most recent request that supplied one, and used by every request after that.
+    system_prompt: Option<String>,
+    /// What's needed to re-run the most recent request.
+    last_request: Option<LastRequest>,
+    /// Replies to the most recent request, if it asked for several and none has been picked yet.
+    pending_choices: Option<PendingChoices>,
+    /// The turns a summary replaced, until the next exchange or undo.
+    before_summary: Option<Vec<ChatTurn>>,
+    /// Pinned messages loaded with the `pins` command. The ones most relevant to the next prompt are
+    /// sent along with it, and then they're all dropped.
+    pins: Vec<PinnedMessage>,
+    /// Set with the `setmodel` command, and used whenever a request doesn't name a model.
+    model: Option<String>,
+    /// When a request was last made with this history. Idle histories are dropped after
+    /// `Config::history_ttl`.
+    last_active: Option<Instant>,
+}
 
-        if appcommand.data.name != "chat" {
-            return Ok(());
-        }
+/// Identifies a chat request by everything that goes into its reply, other than who asked. Prompts
+/// that only differ in case or spacing are treated as the same.
+fn response_cache_key(model: &str, system_prompt: Option<&str>, history: &[ChatTurn], prompt: &str, images: &[String], options: &CompletionOptions) -> String {
+    let normalized_prompt = prompt.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{model}\n{system_prompt:?}\n{images:?}\n{options:?}\n").as_bytes());
+    for turn in history {
+        hasher.update(format!("{}: {:?}\n", turn.role.as_str(), turn.content).as_bytes());
+    }
+    hasher.update(normalized_prompt.as_bytes());
+    hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+}
 
-        let model = appcommand.data.options.iter().find(|o| o.name == "model").ok_or(None)?
-            .value.as_ref().expect("model to be present")
-            .as_str().expect("a str");
-        let prompt = appcommand.data.options.iter().find(|o| o.name == "prompt").ok_or(None)?
-            .value.as_ref().expect("prompt to be present")
-            .as_str().expect("a str");
+#[derive(Debug)]
+struct CachedReplies {
+    stored_at: Instant,
+    used_at: Instant,
+    choices: Vec<String>,
+}
 
-        let gpt_response = self.chat(appcommand.user.id, appcommand.user.name.as_str(), model, prompt).await?;
+/// Replies to recent chat requests, so that asking the exact same thing again doesn't go to OpenAI.
+/// Once full, the least recently used replies make room.
+struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedReplies>>,
+}
 
-        let response_result = appcommand.create_followup_message(ctx, |m| {
-            m
-                .content(format!("{prompt}{gpt_response}"))
-                .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
-        }).await;
+impl ResponseCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self { capacity, ttl, entries: Mutex::new(HashMap::new()) }
+    }
 
-        match response_result {
-            Ok(_) => {
-                Ok(())
-            },
-            Err(_) => {
-                log::error!("Something went wrong sending the message...");
-                Err(None)
-            },
+    fn get(&self, key: &str, now: Instant) -> Option<Vec<String>> {
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(key)?;
+        if now.saturating_duration_since(entry.stored_at) >= self.ttl {
+            entries.remove(key);
+            return None;
         }
+        entry.used_at = now;
+        Some(entry.choices.clone())
     }
 
-    async fn handle_message_and_errors(&self, ctx: Context, msg: Message) {
-        log::info!("BEGIN ui=discord_classic message={msg:?}");
-        let msg_id = msg.id;
-        match self.handle_message(&ctx, &msg).await {
-            Ok(_) => {
-                log::info!("COMPLETE ui=discord_classic message={msg_id:?} outcome=success");
-            },
-            Err(e0) => {
-                let message = e0.as_ref().map(|e| e.as_ref()).unwrap_or("An error occurred");
-                match msg.reply(ctx, message).await {
-                    Ok(_) => {
-                        log::error!("COMPLETE ui=discord_classic message={msg_id:?} outcome=error error={e0:?} user_error=false");
-                    },
-                    Err(e1) => {
-                        log::error!("COMPLETE ui=discord_classic message={msg_id:?} outcome=error primary_error={e0:?} secondary_error={e1:?} user_error=false");
-                    },
-                }
-            },
+    fn insert(&self, key: String, choices: Vec<String>, now: Instant) {
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let least_recent = entries.iter().min_by_key(|(_, entry)| entry.used_at).map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                entries.remove(&least_recent);
+            }
         }
+        entries.insert(key, CachedReplies { stored_at: now, used_at: now, choices });
     }
+}
 
-    async fn handle_message(&self, ctx: &Context, msg: &Message) -> Result<(), Option<Cow<'static, str>>> {
-        if msg.content.as_str() == "-clear" {
-            self.clear(msg.author.id).await?;
-            msg.reply(ctx, "Chat history cleared.").await.ok().ok_or(None)?;
-            return Ok(());
-        }
+type ChatHistories = Mutex<HashMap<HistoryKey, Arc<Mutex<ChatHistory>>>>;
 
-        if !msg.content.as_str().starts_with("-chat ") {
-            return Ok(());
-        }
+/// How often idle chat histories are looked for.
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-        let mut pieces = msg.content.as_str().splitn(3, |c: char| c.is_whitespace());
+/// Drops chat histories that haven't been used in `ttl`, every [`HISTORY_PRUNE_INTERVAL`].
+async fn prune_idle_histories(histories: Arc<ChatHistories>, ttl: Duration) {
+    let mut interval = tokio::time::interval(HISTORY_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut histories = histories.lock();
+        let before = histories.len();
+        // A history that's locked is being used, so it's not idle.
+        histories.retain(|_, history| history.try_lock().is_none_or(|history| {
+            history.last_active.is_some_and(|last_active| now.saturating_duration_since(last_active) < ttl)
+        }));
+        log::info!("PRUNE pruned={} remaining={}", before - histories.len(), histories.len());
+    }
+}
 
-        pieces.next();
+#[derive(Debug, Clone)]
+struct PinnedMessage {
+    content: String,
+    embedding: Arc<Vec<f32>>,
+}
 
-        let Some(model) = pieces.next() else {
-            log::warn!("Model should be present and be one of: `davinci`, `curie`, `babbage`, and `ada`. Found nothing.");
-            return Err(Some("Model should be present and be one of: `davinci`, `curie`, `babbage`, and `ada`.".into()));
-        };
-        if ["davinci", "curie", "babbage", "ada"].iter().all(|s| &model != s) {
-            log::warn!("Model should be one of: `davinci`, `curie`, `babbage`, and `ada`. Found `{model}`.");
-            return Err(Some(format!("Model should be one of: `davinci`, `curie`, `babbage`, and `ada`. Found `{model}`.").into()));
-        }
-        if model != "davinci" {
-            log::warn!("Only `davinci` works. Found `{model}`.");
-            return Err(Some(format!("Only `davinci` works. Found `{model}`.").into()));
+impl ChatHistory {
+    /// Appends a prompt and its reply as the most recent request's turns, forgetting the oldest turns
+    /// beyond `max_turns`.
+    fn push_exchange(&mut self, user_name: &str, prompt: &str, model: &str, reply: &str, max_turns: usize) {
+        let turn_start = self.turns.len();
+        let at = chrono::Utc::now();
+        // Building on a summary means it's there to stay.
+        self.before_summary = None;
+        self.turns.push(ChatTurn { role: Role::User, name: user_name.to_owned(), content: prompt.to_owned(), at });
+        self.turns.push(ChatTurn { role: Role::Assistant, name: model.to_owned(), content: reply.to_owned(), at });
+        let forgotten = cap_turns(&mut self.turns, max_turns);
+        if let Some(last_request) = self.last_request.as_mut() {
+            last_request.turn_start = turn_start.saturating_sub(forgotten);
         }
+    }
+}
 
-        let Some(prompt) = pieces.next() else {
-            log::warn!("A prompt is needed to give to the AI.");
-            return Err(Some("A prompt is needed to give to the AI.".into()));
-        };
+/// Stands in for the user's prompt in a [`GuildConfig::prompt_template`].
+const PROMPT_PLACEHOLDER: &str = "{prompt}";
 
-        let in_progress_message = msg.reply(ctx, "Thinking...").await.ok();
-        if in_progress_message.is_none() {
-            log::error!("Failed to send in progress message. Continuing.");
-        }
+fn validate_prompt_template(template: &str) -> Result<&str, BotError> {
+    if template.contains(PROMPT_PLACEHOLDER) {
+        return Ok(template);
+    }
+    log::warn!("Prompt template is missing `{PROMPT_PLACEHOLDER}`. Found `{template}`.");
+    Err(BotError::UserInput(format!("The prompt template needs a `{PROMPT_PLACEHOLDER}` where the prompt goes, like `Answer concisely. {PROMPT_PLACEHOLDER}`.")))
+}
 
-        let response = self.chat(msg.author.id, msg.author.name.as_str(), model, prompt).await?;
+/// Puts `prompt` into `template`. Only the template is searched for the placeholder, so a prompt
+/// that happens to contain it is left alone.
+fn apply_prompt_template<'a>(template: Option<&str>, prompt: &'a str) -> Cow<'a, str> {
+    match template {
+        Some(template) => template.replace(PROMPT_PLACEHOLDER, prompt).into(),
+        None => prompt.into(),
+    }
+}
 
-        if let Some(in_progress_message) = in_progress_message {
-            if in_progress_message.delete(ctx).await.ok().is_none() {
-                log::error!("Failed to delete in progress message. Continuing.");
-            }
-        }
+/// Replies waiting for the user to pick the one to keep.
+#[derive(Debug, Clone)]
+struct PendingChoices {
+    user_name: String,
+    model: String,
+    prompt: String,
+    choices: Vec<String>,
+}
 
-        msg.channel_id.send_message(ctx, |msg_builder| {
-            msg_builder
-                .content(format!("{prompt}{response}"))
-                .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
-                .reference_message(msg)
-        }).await.ok().ok_or(None)?;
+/// Defaults set by a guild's managers, used whenever a request doesn't give its own. These take
+/// priority over the ones in [`Config`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct GuildConfig {
+    model: Option<String>,
+    temperature: Option<f64>,
+    system_prompt: Option<String>,
+    /// Wraps every prompt before it's sent, in place of [`PROMPT_PLACEHOLDER`].
+    prompt_template: Option<String>,
+    reply_format: Option<ReplyFormat>,
+}
 
-        Ok(())
+impl GuildConfig {
+    fn describe(&self) -> String {
+        let model = self.model.as_deref().map(|model| format!("`{model}`")).unwrap_or_else(|| "(not set)".to_owned());
+        let temperature = self.temperature.map(|temperature| temperature.to_string()).unwrap_or_else(|| "(not set)".to_owned());
+        let system_prompt = self.system_prompt.as_deref().unwrap_or("(not set)");
+        let prompt_template = self.prompt_template.as_deref().unwrap_or("(not set)");
+        let reply_format = self.reply_format.map_or("(not set)", ReplyFormat::as_str);
+        format!("Model: {model}\nTemperature: {temperature}\nSystem prompt: {system_prompt}\nPrompt template: {prompt_template}\nReply format: {reply_format}")
     }
 }
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn ready(&self, ctx: Context, data_about_bot: Ready) {
-        // TODO
-        log::info!("Setting up slash commands.");
-
-        // TODO What should happen about errors?
-        log::info!("Setting up global commands.");
-        Command::set_global_application_commands(&ctx, |commands| commands
-            .create_application_command(|command| {
-                command
-                    .name("chat")
-                    .description("Chat with an AI model.")
-                    .create_option(|option| {
-                        option
-                            .name("model")
-                            .description("name of the model to user")
-                            .kind(CommandOptionType::String)
-                            .add_string_choice("Davinci", "davinci")
-                            .set_autocomplete(false)
-                            .required(true)
-                    })
-                    .create_option(|option| {
-                        option
-                            .name("prompt")
-                            .description("Prompt to pass onto the model")
-                            .kind(CommandOptionType::String)
-                            .set_autocomplete(false)
-                            .required(true)
-                    })
-            })
-            .create_application_command(|command| {
-                command.name("clear").description("Clear chat history")
-            })
-        ).await.unwrap();
+/// Every guild's configuration, saved to a file after every change.
+struct GuildConfigs {
+    path: std::path::PathBuf,
+    /// By guild ID.
+    configs: Mutex<HashMap<u64, GuildConfig>>,
+}
+
+impl GuildConfigs {
+    /// Picks up the configurations saved at `path`, if there are any.
+    fn load(path: &str) -> Self {
+        Self { path: path.into(), configs: Mutex::new(load_json(path, "guild configurations")) }
+    }
+
+    fn get(&self, guild_id: GuildId) -> Option<GuildConfig> {
+        self.configs.lock().get(&guild_id.0).cloned()
+    }
+
+    /// Runs `f` on `guild_id`'s configuration, then saves them all.
+    fn update<T>(&self, guild_id: GuildId, f: impl FnOnce(&mut GuildConfig) -> T) -> T {
+        let mut configs = self.configs.lock();
+        let result = f(configs.entry(guild_id.0).or_default());
+        // Saved under the lock, so that an older change can't be written over a newer one.
+        if let Err(e) = save_json(self.path.as_path(), &*configs) {
+            log::error!("Failed to save the guild configurations to {}. Continuing. Error: {e:?}", self.path.display());
+        }
+        result
+    }
+
+    fn remove(&self, guild_id: GuildId) {
+        let mut configs = self.configs.lock();
+        if configs.remove(&guild_id.0).is_none() {
+            return;
+        }
+        if let Err(e) = save_json(self.path.as_path(), &*configs) {
+            log::error!("Failed to save the guild configurations to {}. Continuing. Error: {e:?}", self.path.display());
+        }
+    }
+}
+
+/// Reads `what` saved at `path` as JSON. Starts from the default if there's nothing there, or it
+/// can't be read.
+fn load_json<T: serde::de::DeserializeOwned + Default>(path: &str, what: &str) -> T {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(bytes.as_slice()).unwrap_or_else(|e| {
+            log::error!("Failed to read the {what} saved in {path}. Starting from scratch. Error: {e:?}");
+            T::default()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+        Err(e) => {
+            log::error!("Failed to open {path}. Starting from scratch. Error: {e:?}");
+            T::default()
+        },
+    }
+}
+
+/// Saves `value` to `path` as JSON. It's written to the side first, so that a crash mid-write can't
+/// lose what was saved before.
+fn save_json(path: &std::path::Path, value: &impl Serialize) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let bytes = serde_json::to_vec(value)?;
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[derive(Debug, Clone)]
+struct LastRequest {
+    user_name: String,
+    model: String,
+    prompt: String,
+    images: Vec<String>,
+    options: CompletionOptions,
+    /// Index of the request's first turn, so that its turns can be popped off.
+    turn_start: usize,
+}
+
+struct Handler {
+    cfg: Arc<Config>,
+    chat_histories: Arc<ChatHistories>,
+    guild_configs: GuildConfigs,
+    usage: Mutex<HashMap<UserId, UsageTotals>>,
+    /// Only present when a monthly budget is set.
+    spend: Option<SpendLedger>,
+    /// Stops streaming replies early, keyed by the message with the prompt. Only the user who sent
+    /// the prompt may stop it.
+    cancellations: Mutex<HashMap<MessageId, (UserId, CancellationToken)>>,
+    /// Limits how many OpenAI requests are in flight at once.
+    request_permits: Semaphore,
+    /// When each user last made a request to OpenAI, for enforcing the cooldown.
+    last_requests: Mutex<HashMap<UserId, Instant>>,
+    /// Chat models OpenAI listed, and when they were fetched.
+    model_list: Mutex<Option<(Instant, Vec<String>)>>,
+    /// Pauses OpenAI requests after repeated failures.
+    circuit: Mutex<CircuitBreaker>,
+    api_keys: KeyPool,
+    /// Only present when response caching is enabled.
+    response_cache: Option<ResponseCache>,
+    /// Numbers the next event handled, for telling apart the logs of events handled at once.
+    request_seq: AtomicU64,
+    /// Set by an admin with `stop_generation`, and cleared with `resume`. While set, nothing is sent
+    /// to OpenAI.
+    halted: AtomicBool,
+    /// Functions the model may call, by name.
+    tools: HashMap<String, Tool>,
+    /// Embeddings of pinned messages, so that loading the same pins again doesn't re-embed them.
+    pin_embeddings: Mutex<HashMap<MessageId, Arc<Vec<f32>>>>,
+    /// The rest of long replies, keyed by the message with the continue button, along with when they
+    /// were held back.
+    continuations: Mutex<HashMap<MessageId, (Instant, Vec<ReplyChunk>)>>,
+    /// Chat prompts that failed, keyed by the message with the error.
+    failed_prompts: Mutex<HashMap<MessageId, FailedPrompt>>,
+    /// Whether the missing Message Content intent has been warned about, so that it's only logged once.
+    warned_missing_content: AtomicBool,
+    /// Whether the slash commands have been registered, so that reconnecting doesn't redo it.
+    commands_registered: AtomicBool,
+    /// Only present when the metrics server is enabled.
+    metrics: Option<Arc<Mutex<Metrics>>>,
+    /// Only present when the dashboard is enabled.
+    recent_requests: Option<Arc<RecentRequests>>,
+    started_at: Instant,
+}
+
+/// Whether `author` is a bot, `bot_id` included. Their messages are never answered, so that a reply
+/// can't set off another.
+fn is_from_bot(author: &User, bot_id: UserId) -> bool {
+    author.bot || author.id == bot_id
+}
+
+/// Whether `msg` looks like Discord withheld its content, which it does when the Message Content
+/// intent isn't enabled in the developer portal. DMs and messages mentioning the bot still have their
+/// content, so only guild messages that aren't mentions count.
+fn is_missing_content(msg: &Message, bot_id: UserId) -> bool {
+    msg.guild_id.is_some()
+        && msg.kind == MessageType::Regular
+        && msg.content.is_empty()
+        && msg.attachments.is_empty()
+        && msg.embeds.is_empty()
+        && msg.sticker_items.is_empty()
+        && !msg.mentions.iter().any(|user| user.id == bot_id)
+}
+
+/// Whether whoever used `appcommand` may change the settings of the guild it was used in.
+fn is_guild_manager(appcommand: &ApplicationCommandInteraction) -> bool {
+    let permissions = appcommand.member.as_ref().and_then(|member| member.permissions);
+    permissions.is_some_and(|permissions| permissions.administrator() || permissions.manage_guild())
+}
+
+/// Lets handlers reach the shard manager, for reporting gateway latency.
+struct ShardManagerContainer;
+
+impl TypeMapKey for ShardManagerContainer {
+    type Value = Arc<serenity::prelude::Mutex<ShardManager>>;
+}
+
+/// Latency of the gateway connection `ctx` came in on, if it's been measured yet.
+async fn gateway_latency(ctx: &Context) -> Option<Duration> {
+    let shard_manager = ctx.data.read().await.get::<ShardManagerContainer>().cloned()?;
+    let shard_manager = shard_manager.lock().await;
+    let runners = shard_manager.runners.lock().await;
+    runners.get(&ShardId(ctx.shard_id))?.latency
+}
+
+/// Most time the health check waits on OpenAI.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds, in seconds, of the latency histogram's buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// Escapes `value` for use as a label value in Prometheus' text exposition format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations at or under each of [`LATENCY_BUCKETS`], so each count includes the ones before it.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Everything served by the metrics endpoint.
+#[derive(Debug, Default)]
+struct Metrics {
+    /// Events handled, by `ui`.
+    requests: BTreeMap<String, u64>,
+    /// Events that ended in an error, by `ui`.
+    errors: BTreeMap<String, u64>,
+    /// Tokens used, by model and kind (`prompt` or `completion`).
+    tokens: BTreeMap<(String, &'static str), u64>,
+    /// How long events took, by `ui`.
+    latency: BTreeMap<String, Histogram>,
+}
+
+impl Metrics {
+    fn observe(&mut self, ui: &str, duration: Duration) {
+        *self.requests.entry(ui.to_owned()).or_default() += 1;
+
+        let secs = duration.as_secs_f64();
+        let histogram = self.latency.entry(ui.to_owned()).or_default();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    fn record_error(&mut self, ui: &str) {
+        *self.errors.entry(ui.to_owned()).or_default() += 1;
+    }
+
+    fn record_tokens(&mut self, model: &str, usage: TokenUsage) {
+        *self.tokens.entry((model.to_owned(), "prompt")).or_default() += usage.prompt_tokens;
+        *self.tokens.entry((model.to_owned(), "completion")).or_default() += usage.completion_tokens;
+    }
+
+    /// Renders everything in Prometheus' text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP chatgpt_requests_total Events handled.\n# TYPE chatgpt_requests_total counter\n");
+        for (ui, count) in &self.requests {
+            let ui = escape_label(ui);
+            out.push_str(format!("chatgpt_requests_total{{ui=\"{ui}\"}} {count}\n").as_str());
+        }
+        out.push_str("# HELP chatgpt_errors_total Events that ended in an error.\n# TYPE chatgpt_errors_total counter\n");
+        for (ui, count) in &self.errors {
+            let ui = escape_label(ui);
+            out.push_str(format!("chatgpt_errors_total{{ui=\"{ui}\"}} {count}\n").as_str());
+        }
+        out.push_str("# HELP chatgpt_tokens_total OpenAI tokens used.\n# TYPE chatgpt_tokens_total counter\n");
+        for ((model, kind), count) in &self.tokens {
+            let model = escape_label(model);
+            out.push_str(format!("chatgpt_tokens_total{{model=\"{model}\",kind=\"{kind}\"}} {count}\n").as_str());
+        }
+        out.push_str("# HELP chatgpt_duration_seconds How long events took.\n# TYPE chatgpt_duration_seconds histogram\n");
+        for (ui, histogram) in &self.latency {
+            let ui = escape_label(ui);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                out.push_str(format!("chatgpt_duration_seconds_bucket{{ui=\"{ui}\",le=\"{bound}\"}} {count}\n").as_str());
+            }
+            out.push_str(format!("chatgpt_duration_seconds_bucket{{ui=\"{ui}\",le=\"+Inf\"}} {}\n", histogram.count).as_str());
+            out.push_str(format!("chatgpt_duration_seconds_sum{{ui=\"{ui}\"}} {}\n", histogram.sum).as_str());
+            out.push_str(format!("chatgpt_duration_seconds_count{{ui=\"{ui}\"}} {}\n", histogram.count).as_str());
+        }
+        out
+    }
+}
+
+/// Serves `metrics` at `/metrics` on `port` until the server fails.
+async fn serve_metrics(port: u16, metrics: Arc<Mutex<Metrics>>) {
+    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let metrics = Arc::clone(&metrics);
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                let response = metrics_response(&request, &metrics.lock());
+                async move { Ok::<_, std::convert::Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = match hyper::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("Failed to bind the metrics server to {addr}. Metrics won't be served. Error: {e:?}");
+            return;
+        },
+    };
+    log::info!("Serving metrics on {addr}.");
+    if let Err(e) = server.serve(make_service).await {
+        log::error!("Metrics server failed. Error: {e:?}");
+    }
+}
+
+fn metrics_response(request: &hyper::Request<hyper::Body>, metrics: &Metrics) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::new(hyper::Body::empty());
+    if request.uri().path() != "/metrics" {
+        *response.status_mut() = hyper::StatusCode::NOT_FOUND;
+        return response;
+    }
+    *response.body_mut() = metrics.render().into();
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"));
+    response
+}
+
+/// A chat request, as listed on the dashboard.
+#[derive(Debug, Clone)]
+struct RequestRecord {
+    at: chrono::DateTime<chrono::Utc>,
+    user_name: String,
+    model: String,
+    latency: Duration,
+    /// Unset if the request succeeded.
+    error: Option<String>,
+}
+
+/// The latest chat requests, newest last. Older ones are dropped once `capacity` is reached.
+struct RecentRequests {
+    capacity: usize,
+    records: Mutex<VecDeque<RequestRecord>>,
+}
+
+impl RecentRequests {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, records: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    fn push(&self, record: RequestRecord) {
+        let mut records = self.records.lock();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Renders the requests as an HTML page, newest first.
+    fn render(&self) -> String {
+        let mut rows = String::new();
+        for record in self.records.lock().iter().rev() {
+            let (outcome, detail) = match record.error.as_deref() {
+                Some(error) => ("error", escape_html(error)),
+                None => ("success", String::new()),
+            };
+            rows.push_str(format!(
+                "<tr class=\"{outcome}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{outcome}</td><td>{detail}</td></tr>\n",
+                record.at.format("%Y-%m-%d %H:%M:%S UTC"),
+                escape_html(record.user_name.as_str()),
+                escape_html(record.model.as_str()),
+                format_duration(chrono::Duration::from_std(record.latency).unwrap_or_else(|_| chrono::Duration::zero())),
+            ).as_str());
+        }
+        format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Recent requests</title><style>\
+            body {{ font-family: sans-serif; }} table {{ border-collapse: collapse; }} td, th {{ padding: 0.25em 0.75em; text-align: left; }} \
+            tr.error {{ background: #fdd; }}</style></head>\n<body><h1>Recent requests</h1>\n\
+            <table><tr><th>Time</th><th>User</th><th>Model</th><th>Latency</th><th>Outcome</th><th>Error</th></tr>\n{rows}</table></body></html>\n",
+        )
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Serves the dashboard of `recent_requests` at `/` on `port` until the server fails. Requests
+/// without `token` as their bearer token are turned away.
+async fn serve_dashboard(port: u16, token: String, recent_requests: Arc<RecentRequests>) {
+    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    // Compared as digests so that how long the comparison takes doesn't give the token away.
+    let token_digest = Sha256::digest(token.as_bytes());
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let recent_requests = Arc::clone(&recent_requests);
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                let response = dashboard_response(&request, token_digest.as_slice(), &recent_requests);
+                async move { Ok::<_, std::convert::Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = match hyper::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("Failed to bind the dashboard server to {addr}. The dashboard won't be served. Error: {e:?}");
+            return;
+        },
+    };
+    log::info!("Serving the dashboard on {addr}.");
+    if let Err(e) = server.serve(make_service).await {
+        log::error!("Dashboard server failed. Error: {e:?}");
+    }
+}
+
+fn dashboard_response(request: &hyper::Request<hyper::Body>, token_digest: &[u8], recent_requests: &RecentRequests) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::new(hyper::Body::empty());
+    if request.uri().path() != "/" {
+        *response.status_mut() = hyper::StatusCode::NOT_FOUND;
+        return response;
+    }
+    let authorized = request.headers().get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| Sha256::digest(given.trim().as_bytes()).as_slice() == token_digest);
+    if !authorized {
+        log::warn!("Turned away a dashboard request without a valid token.");
+        *response.status_mut() = hyper::StatusCode::UNAUTHORIZED;
+        response.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, hyper::header::HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    *response.body_mut() = recent_requests.render().into();
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/html; charset=utf-8"));
+    response
+}
+
+/// The API key is left out, since it changes from request to request. See [`Handler::authorize`].
+fn build_openai_client(org_id: Option<&str>, connect_timeout: Duration) -> Result<reqwest::Client, ()> {
+    let mut default_client_headers = HeaderMap::new();
+    if let Some(org_id) = org_id {
+        default_client_headers.insert("OpenAI-Organization", org_id.try_into().expect("organization header is valid"));
+    }
+
+    let res = reqwest::Client::builder()
+        .default_headers(default_client_headers)
+        .connect_timeout(connect_timeout)
+        .build();
+
+    res.ok().ok_or(())
+}
+
+struct ModelInfo {
+    name: &'static str,
+    id: &'static str,
+    /// Size of the context window, which is also the most tokens that can be requested.
+    context_window: u32,
+    /// USD per thousand prompt tokens.
+    prompt_price: f64,
+    /// USD per thousand completion tokens.
+    completion_price: f64,
+    /// Whether prompts may come with images.
+    vision: bool,
+    /// Whether `temperature`, `top_p` and the penalties are accepted. Reasoning models reject them.
+    sampling: bool,
+    /// Whether tools can be offered.
+    tools: bool,
+    /// What the request calls the most tokens to generate.
+    max_tokens_param: &'static str,
+    /// Whether the likeliest tokens can be asked for along with the reply.
+    logprobs: bool,
+    /// Whether the reply can be constrained to a JSON object.
+    json_mode: bool,
+}
+
+/// Every model the bot will talk to. Both the slash command choices and the classic command
+/// validation are generated from this.
+const MODELS: &[ModelInfo] = &[
+    ModelInfo { name: "Davinci", id: "davinci", context_window: 4097, prompt_price: 0.02, completion_price: 0.02, vision: false, sampling: true, tools: false, max_tokens_param: "max_tokens", logprobs: true, json_mode: false },
+    ModelInfo { name: "GPT-3.5 Turbo", id: "gpt-3.5-turbo", context_window: 4096, prompt_price: 0.0015, completion_price: 0.002, vision: false, sampling: true, tools: true, max_tokens_param: "max_tokens", logprobs: true, json_mode: true },
+    ModelInfo { name: "GPT-4", id: "gpt-4", context_window: 8192, prompt_price: 0.03, completion_price: 0.06, vision: false, sampling: true, tools: true, max_tokens_param: "max_tokens", logprobs: true, json_mode: false },
+    ModelInfo { name: "GPT-4 Vision", id: "gpt-4-vision-preview", context_window: 128000, prompt_price: 0.01, completion_price: 0.03, vision: true, sampling: true, tools: false, max_tokens_param: "max_tokens", logprobs: false, json_mode: false },
+    ModelInfo { name: "o1 Preview", id: "o1-preview", context_window: 128000, prompt_price: 0.015, completion_price: 0.06, vision: false, sampling: false, tools: false, max_tokens_param: "max_completion_tokens", logprobs: false, json_mode: false },
+    ModelInfo { name: "o1 Mini", id: "o1-mini", context_window: 128000, prompt_price: 0.003, completion_price: 0.012, vision: false, sampling: false, tools: false, max_tokens_param: "max_completion_tokens", logprobs: false, json_mode: false },
+];
+
+const DEFAULT_MAX_TOKENS: u32 = 500;
+
+fn model_info(model: &str) -> Option<&'static ModelInfo> {
+    MODELS.iter().find(|info| info.id == model)
+}
+
+fn is_known_model(model: &str) -> bool {
+    model_info(model).is_some()
+}
+
+fn validate_model(model: &str) -> Result<(), BotError> {
+    if is_known_model(model) {
+        return Ok(());
+    }
+    let models = model_list();
+    log::warn!("Model should be one of: {models}. Found `{model}`.");
+    Err(BotError::UserInput(format!("Model should be one of: {models}. Found `{model}`.")))
+}
+
+/// Makes sure `model` can see images, if there are any.
+fn validate_images(model: &str, images: &[String]) -> Result<(), BotError> {
+    if images.is_empty() || model_info(model).is_some_and(|info| info.vision) {
+        return Ok(());
+    }
+    let vision_models = MODELS.iter().filter(|info| info.vision).map(|info| format!("`{}`", info.id)).collect::<Vec<_>>().join(", ");
+    log::warn!("Model `{model}` can't take images, but {} were attached.", images.len());
+    Err(BotError::UserInput(format!("`{model}` can't see images. Try one of: {vision_models}.")))
+}
+
+/// Makes sure the user didn't set sampling options for a model that rejects them. Defaults are left
+/// out of the request instead.
+fn validate_sampling(model: &str, requested: &RequestedOptions) -> Result<(), BotError> {
+    if model_info(model).is_none_or(|info| info.sampling) {
+        return Ok(());
+    }
+    let unsupported: Vec<_> = [
+        ("temperature", requested.temperature.is_some()),
+        ("top_p", requested.top_p.is_some()),
+        ("presence_penalty", requested.presence_penalty.is_some()),
+        ("frequency_penalty", requested.frequency_penalty.is_some()),
+    ].into_iter().filter(|(_, set)| *set).map(|(name, _)| format!("`{name}`")).collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    let unsupported = unsupported.join(", ");
+    log::warn!("Model `{model}` doesn't take {unsupported}.");
+    Err(BotError::UserInput(format!("`{model}` doesn't support {unsupported}. Leave them unset, or pick another model.")))
+}
+
+fn model_list() -> String {
+    MODELS.iter().map(|info| format!("`{}`", info.id)).collect::<Vec<_>>().join(", ")
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelListResponse {
+    data: Vec<ModelListEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelListEntry {
+    id: String,
+}
+
+/// Whether `model`, as listed by OpenAI, works with the chat completions endpoint.
+fn is_chat_model(model: &str) -> bool {
+    (model.starts_with("gpt-") || model.starts_with("o1")) && !model.contains("instruct")
+}
+
+/// Discord allows at most this many autocomplete suggestions.
+const AUTOCOMPLETE_LIMIT: usize = 25;
+
+/// Models whose identifier or display name starts with `partial`, which should already be lowercase.
+fn matching_models(partial: &str) -> impl Iterator<Item = &'static ModelInfo> + '_ {
+    MODELS.iter()
+        .filter(move |info| info.id.starts_with(partial) || info.name.to_lowercase().starts_with(partial))
+        .take(AUTOCOMPLETE_LIMIT)
+}
+
+fn validate_max_tokens(model: &str, max_tokens: i64) -> Result<u32, BotError> {
+    let Some(info) = model_info(model) else {
+        log::warn!("Unknown model `{model}` when validating max tokens.");
+        return Err(BotError::Internal);
+    };
+    let limit = info.context_window;
+    match u32::try_from(max_tokens) {
+        Ok(max_tokens) if (1..=limit).contains(&max_tokens) => Ok(max_tokens),
+        _ => {
+            log::warn!("Max tokens for `{model}` should be between 1 and {limit}. Found `{max_tokens}`.");
+            Err(BotError::UserInput(format!("Max tokens for `{model}` should be between 1 and {limit}, since that's its context window. Found `{max_tokens}`.")))
+        },
+    }
+}
+
+/// Models that only work with the legacy `/v1/completions` endpoint. Everything else goes through
+/// `/v1/chat/completions`.
+const LEGACY_COMPLETION_MODELS: &[&str] = &["davinci"];
+
+fn is_legacy_completion_model(model: &str) -> bool {
+    LEGACY_COMPLETION_MODELS.contains(&model)
+}
+
+/// Approximates the number of BPE tokens in `text`. Common English words are a single token, with
+/// longer words costing roughly one token per four characters.
+fn count_tokens(text: &str) -> usize {
+    text.split_whitespace().map(|word| word.chars().count().div_ceil(4)).sum()
+}
+
+/// Forgets the oldest turns beyond `max_turns`, returning how many were forgotten.
+fn cap_turns(turns: &mut Vec<ChatTurn>, max_turns: usize) -> usize {
+    let excess = turns.len().saturating_sub(max_turns);
+    turns.drain(..excess);
+    excess
+}
+
+/// Drops whole turns from the front of `history` until it fits in `budget` tokens.
+fn trim_history(history: &[ChatTurn], budget: usize) -> &[ChatTurn] {
+    let mut tokens: usize = history.iter().map(ChatTurn::count_tokens).sum();
+    let mut relevant = history;
+    while tokens > budget {
+        let Some((oldest, rest)) = relevant.split_first() else {
+            break;
+        };
+        tokens -= oldest.count_tokens();
+        relevant = rest;
+    }
+    relevant
+}
+
+/// Range of temperatures OpenAI accepts.
+const TEMPERATURE_RANGE: std::ops::RangeInclusive<f64> = 0.0..=2.0;
+
+const TOP_P_RANGE: std::ops::RangeInclusive<f64> = 0.0..=1.0;
+
+fn validate_top_p(top_p: f64) -> Result<f64, BotError> {
+    if TOP_P_RANGE.contains(&top_p) {
+        return Ok(top_p);
+    }
+    log::warn!("Top p should be between {} and {}. Found `{top_p}`.", TOP_P_RANGE.start(), TOP_P_RANGE.end());
+    Err(BotError::UserInput(format!("Top p should be between {} and {}. Found `{top_p}`.", TOP_P_RANGE.start(), TOP_P_RANGE.end())))
+}
+
+/// What to tell a user who set both `temperature` and `top_p`, which OpenAI recommends against. The
+/// request still goes through.
+fn sampling_warning(requested: &RequestedOptions) -> Option<&'static str> {
+    if requested.temperature.is_some() && requested.top_p.is_some() {
+        log::info!("Both temperature and top p were set.");
+        return Some("Note: you set both `temperature` and `top_p`. OpenAI recommends changing only one of them.");
+    }
+    None
+}
+
+fn validate_temperature(temperature: f64) -> Result<f64, BotError> {
+    if TEMPERATURE_RANGE.contains(&temperature) {
+        return Ok(temperature);
+    }
+    log::warn!("Temperature should be between {} and {}. Found `{temperature}`.", TEMPERATURE_RANGE.start(), TEMPERATURE_RANGE.end());
+    Err(BotError::UserInput(format!("Temperature should be between {} and {}. Found `{temperature}`.", TEMPERATURE_RANGE.start(), TEMPERATURE_RANGE.end())))
+}
+
+/// Range of presence and frequency penalties OpenAI accepts.
+const PENALTY_RANGE: std::ops::RangeInclusive<f64> = -2.0..=2.0;
+
+/// Checks a penalty, where `name` is how the user would refer to it.
+fn validate_penalty(name: &str, penalty: f64) -> Result<f64, BotError> {
+    if PENALTY_RANGE.contains(&penalty) {
+        return Ok(penalty);
+    }
+    log::warn!("{name} should be between {} and {}. Found `{penalty}`.", PENALTY_RANGE.start(), PENALTY_RANGE.end());
+    Err(BotError::UserInput(format!("{name} should be between {} and {}. Found `{penalty}`.", PENALTY_RANGE.start(), PENALTY_RANGE.end())))
+}
+
+/// Most replies a single request may ask for to pick between.
+const MAX_CHOICES: u32 = 4;
+
+fn validate_choices(n: i64) -> Result<u32, BotError> {
+    match u32::try_from(n) {
+        Ok(n) if (1..=MAX_CHOICES).contains(&n) => Ok(n),
+        _ => {
+            log::warn!("Choices should be between 1 and {MAX_CHOICES}. Found `{n}`.");
+            Err(BotError::UserInput(format!("Choices should be between 1 and {MAX_CHOICES}. Found `{n}`.")))
+        },
+    }
+}
+
+/// Longest language name accepted. Anything longer is more likely a prompt than a language.
+const MAX_LANGUAGE_CHARS: usize = 40;
+
+fn validate_language(language: &str) -> Result<&str, BotError> {
+    let language = language.trim();
+    if !language.is_empty() && language.chars().count() <= MAX_LANGUAGE_CHARS {
+        return Ok(language);
+    }
+    log::warn!("Language should be a name of at most {MAX_LANGUAGE_CHARS} characters, or `auto`. Found `{language}`.");
+    Err(BotError::UserInput(format!("Language should be a name of at most {MAX_LANGUAGE_CHARS} characters, or `auto`. Found `{language}`.")))
+}
+
+/// Tells the model which language to reply in. This is about the model's replies, whereas
+/// [`Locale`] is about the bot's own messages.
+fn language_directive(language: &str) -> String {
+    if language.eq_ignore_ascii_case("auto") {
+        "Always reply in the same language as the user's latest message.".to_owned()
+    } else {
+        format!("Always reply in {language}, whatever language the user writes in.")
+    }
+}
+
+fn validate_prompt_length(prompt: &str, max_chars: usize) -> Result<(), BotError> {
+    let chars = prompt.chars().count();
+    if max_chars == 0 || chars <= max_chars {
+        return Ok(());
+    }
+    log::warn!("Prompt is {chars} characters, over the limit of {max_chars}.");
+    Err(BotError::UserInput(format!("That prompt is {chars} characters long, but prompts can be at most {max_chars}.")))
+}
+
+/// Most alternatives per token that can be asked for. Legacy completions don't allow more.
+const MAX_LOGPROBS: u8 = 5;
+
+fn validate_logprobs(model: &str, logprobs: i64) -> Result<u8, BotError> {
+    if model_info(model).is_some_and(|info| !info.logprobs) {
+        log::warn!("Model `{model}` doesn't return logprobs.");
+        return Err(BotError::UserInput(format!("`{model}` doesn't support `logprobs`. Leave it unset, or pick another model.")));
+    }
+    match u8::try_from(logprobs) {
+        Ok(logprobs) if logprobs <= MAX_LOGPROBS => Ok(logprobs),
+        _ => {
+            log::warn!("Logprobs should be between 0 and {MAX_LOGPROBS}. Found `{logprobs}`.");
+            Err(BotError::UserInput(format!("Logprobs should be between 0 and {MAX_LOGPROBS}. Found `{logprobs}`.")))
+        },
+    }
+}
+
+fn validate_json_mode(model: &str) -> Result<(), BotError> {
+    if model_info(model).is_none_or(|info| info.json_mode) {
+        return Ok(());
+    }
+    let json_models = MODELS.iter().filter(|info| info.json_mode).map(|info| format!("`{}`", info.id)).collect::<Vec<_>>().join(", ");
+    log::warn!("Model `{model}` doesn't have a JSON mode.");
+    Err(BotError::UserInput(format!("`{model}` doesn't support `json`. Try one of: {json_models}.")))
+}
+
+/// OpenAI refuses JSON mode unless the messages ask for JSON themselves.
+fn validate_json_mention(system_prompt: Option<&str>, prompt: &str) -> Result<(), BotError> {
+    let mentions_json = |text: &str| text.to_lowercase().contains("json");
+    if mentions_json(prompt) || system_prompt.is_some_and(mentions_json) {
+        return Ok(());
+    }
+    log::warn!("JSON mode was asked for without mentioning JSON.");
+    Err(BotError::UserInput("With `json`, the prompt or system prompt has to ask for JSON, like `Reply in JSON with a list of names`.".into()))
+}
+
+/// Makes sure a reply in JSON mode is actually JSON. Replies cut short by the token limit aren't.
+fn validate_json_reply(reply: &str) -> Result<(), BotError> {
+    match serde_json::from_str::<serde_json::Value>(reply) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::warn!("Reply in JSON mode isn't JSON. Error: {e}");
+            Err(BotError::OpenAi("The model's reply wasn't valid JSON, so it wasn't kept. If it was cut off, try a higher `max_tokens`.".into()))
+        },
+    }
+}
+
+/// OpenAI accepts at most this many stop sequences.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Parses comma separated stop sequences, ignoring empty entries.
+fn parse_stop(value: &str) -> Vec<String> {
+    value.split(',').filter(|stop| !stop.is_empty()).map(str::to_owned).collect()
+}
+
+fn validate_stop(stop: Vec<String>) -> Result<Vec<String>, BotError> {
+    if stop.len() <= MAX_STOP_SEQUENCES {
+        return Ok(stop);
+    }
+    log::warn!("At most {MAX_STOP_SEQUENCES} stop sequences can be given. Found {}.", stop.len());
+    Err(BotError::UserInput(format!("At most {MAX_STOP_SEQUENCES} stop sequences can be given. Found {}.", stop.len())))
+}
+
+/// Per-request knobs that get passed through to the completion request body.
+#[derive(Debug, Clone)]
+struct CompletionOptions {
+    temperature: f64,
+    max_tokens: u32,
+    /// Replaces (rather than adds to) the conversation's system prompt, including the default one.
+    system_prompt: Option<String>,
+    /// Where the model stops generating. Left out of the request when empty.
+    stop: Vec<String>,
+    /// Nucleus sampling. Left out of the request when unset.
+    top_p: Option<f64>,
+    /// Left out of the request when unset.
+    presence_penalty: Option<f64>,
+    /// Left out of the request when unset.
+    frequency_penalty: Option<f64>,
+    /// How many replies to generate. When there's more than one, none are kept in the history until
+    /// the user picks one.
+    n: u32,
+    /// Makes sampling repeatable, as far as OpenAI manages. Left out of the request when unset.
+    seed: Option<i64>,
+    /// How many of the likeliest tokens to return for each position of the reply. Left out of the
+    /// request when unset.
+    logprobs: Option<u8>,
+    /// Language the model is told to reply in, or `auto` for the prompt's.
+    reply_language: Option<String>,
+    /// Constrains the reply to a JSON object.
+    json: bool,
+}
+
+/// Options as the user gave them, before falling back to defaults or being validated. Both slash and
+/// classic commands fill this in, so new options only need resolving in one place.
+#[derive(Debug, Default)]
+struct RequestedOptions<'a> {
+    model: Option<&'a str>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<i64>,
+    system_prompt: Option<&'a str>,
+    /// Comma separated.
+    stop: Option<&'a str>,
+    presence_penalty: Option<f64>,
+    frequency_penalty: Option<f64>,
+    choices: Option<i64>,
+    seed: Option<i64>,
+    logprobs: Option<i64>,
+    /// A language name, or `auto`.
+    language: Option<&'a str>,
+    json: Option<bool>,
+}
+
+/// What a chat request got back.
+#[derive(Debug)]
+struct ChatReplies {
+    choices: Vec<String>,
+    /// For the first choice, if they were asked for and the model gave them.
+    logprobs: Option<Vec<TokenLogprobs>>,
+    /// The model that replied, which is the fallback if the one asked for was overloaded.
+    model: String,
+}
+
+/// A prompt and everything needed to answer it, however it was asked.
+#[derive(Debug)]
+struct ChatRequest<'a> {
+    key: HistoryKey,
+    user_name: &'a str,
+    model: String,
+    /// What's sent to the model, which may have more context than what the user wrote.
+    prompt: &'a str,
+    /// URLs of images sent along with the prompt. Only vision models can take these.
+    images: Vec<String>,
+    options: CompletionOptions,
+    /// Turns rebuilt from the Discord replies the prompt answers, oldest first. When set, these are
+    /// sent instead of the stored history.
+    reply_chain: Option<Vec<ChatTurn>>,
+    /// Told the number of the next attempt whenever a request to OpenAI is about to be retried.
+    retry_notices: Option<watch::Sender<u32>>,
+    /// Whether an overloaded model can be swapped for its fallback in [`Config::fallback_models`].
+    fallback: bool,
+}
+
+/// Everything about a chat request that doesn't depend on the model it's sent to. It's worked out
+/// once, so that falling back to another model doesn't redo moderation or use up the pins again.
+struct PreparedChat<'a> {
+    system_prompt: Option<&'a str>,
+    /// Wrapped in the guild's prompt template.
+    sent_prompt: &'a str,
+    /// How the prompt ends a legacy completion's transcript.
+    prompt_line: &'a str,
+    /// Trimmed to the history token budget.
+    history: &'a [ChatTurn],
+}
+
+/// Adds the options that are left out of the request body when unset. The sampling options are also
+/// left out for models without `sampling`.
+fn add_optional_options(body: &mut serde_json::Value, options: &CompletionOptions, sampling: bool) {
+    if !options.stop.is_empty() {
+        body["stop"] = options.stop.clone().into();
+    }
+    if let Some(seed) = options.seed {
+        body["seed"] = seed.into();
+    }
+    if !sampling {
+        return;
+    }
+    body["temperature"] = options.temperature.into();
+    if let Some(top_p) = options.top_p {
+        body["top_p"] = top_p.into();
+    }
+    if let Some(presence_penalty) = options.presence_penalty {
+        body["presence_penalty"] = presence_penalty.into();
+    }
+    if let Some(frequency_penalty) = options.frequency_penalty {
+        body["frequency_penalty"] = frequency_penalty.into();
+    }
+}
+
+fn build_chat_completion(model: &str, system_prompt: Option<&str>, history: &[ChatTurn], prompt: &str, images: &[String], options: &CompletionOptions) -> serde_json::Value {
+    let mut messages = Vec::with_capacity(history.len() + 2);
+    if let Some(system_prompt) = system_prompt {
+        messages.push(serde_json::json!({
+            "role": "system",
+            "content": system_prompt,
+        }));
+    }
+    for turn in history {
+        messages.push(serde_json::json!({
+            "role": turn.role.as_str(),
+            "content": turn.content,
+        }));
+    }
+    if images.is_empty() {
+        messages.push(serde_json::json!({
+            "role": "user",
+            "content": prompt,
+        }));
+    } else {
+        let mut content = vec![serde_json::json!({ "type": "text", "text": prompt })];
+        content.extend(images.iter().map(|url| serde_json::json!({ "type": "image_url", "image_url": { "url": url } })));
+        messages.push(serde_json::json!({
+            "role": "user",
+            "content": content,
+        }));
+    }
+
+    let info = model_info(model);
+    let mut body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "n": options.n,
+    });
+    body[info.map_or("max_tokens", |info| info.max_tokens_param)] = options.max_tokens.into();
+    add_optional_options(&mut body, options, info.is_none_or(|info| info.sampling));
+    if let Some(logprobs) = options.logprobs {
+        body["logprobs"] = true.into();
+        body["top_logprobs"] = logprobs.into();
+    }
+    if options.json {
+        body["response_format"] = serde_json::json!({ "type": "json_object" });
+    }
+    body
+}
+
+fn build_completion(prompt: &str, options: &CompletionOptions) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": "text-davinci-003",
+        "prompt": prompt,
+        "max_tokens": options.max_tokens,
+        "suffix": null,
+        "n": options.n,
+    });
+    add_optional_options(&mut body, options, true);
+    if let Some(logprobs) = options.logprobs {
+        body["logprobs"] = logprobs.into();
+    }
+    body
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+    usage: Option<TokenUsage>,
+    /// Identifies the backend configuration, which has to match for a seed to reproduce a reply.
+    system_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+struct TokenUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Estimated cost in USD, using `model`'s prices.
+    fn estimated_cost(&self, model: &str) -> f64 {
+        let Some(info) = model_info(model) else {
+            return 0.0;
+        };
+        (self.prompt_tokens as f64 * info.prompt_price + self.completion_tokens as f64 * info.completion_price) / 1000.0
+    }
+}
+
+/// Running totals for a single user, across every conversation they've had. Clearing history does
+/// not reset these.
+#[derive(Debug, Clone, Copy, Default)]
+struct UsageTotals {
+    requests: u64,
+    tokens: TokenUsage,
+    estimated_cost: f64,
+}
+
+impl UsageTotals {
+    fn record(&mut self, model: &str, usage: TokenUsage) {
+        self.requests += 1;
+        self.tokens.prompt_tokens += usage.prompt_tokens;
+        self.tokens.completion_tokens += usage.completion_tokens;
+        self.tokens.total_tokens += usage.total_tokens;
+        self.estimated_cost += usage.estimated_cost(model);
+    }
+}
+
+/// Estimated spend over one calendar month. Starts over once the month is up.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MonthlySpend {
+    /// Like `2024-05`.
+    month: String,
+    total: f64,
+    /// By user ID.
+    users: HashMap<u64, f64>,
+}
+
+/// The month's spend, saved to a file after every change.
+struct SpendLedger {
+    path: std::path::PathBuf,
+    spend: Mutex<MonthlySpend>,
+}
+
+fn current_month() -> String {
+    chrono::Utc::now().format("%Y-%m").to_string()
+}
+
+impl SpendLedger {
+    /// Picks up the spend saved at `path`, if there is any.
+    fn load(path: &str) -> Self {
+        Self { path: path.into(), spend: Mutex::new(load_json(path, "spend")) }
+    }
+
+    /// Runs `f` on this month's spend, starting it over first if a new month has begun.
+    fn with_current<T>(&self, f: impl FnOnce(&mut MonthlySpend) -> T) -> T {
+        let mut spend = self.spend.lock();
+        let month = current_month();
+        if spend.month != month {
+            log::info!("SPEND-RESET month={month} previous_month={:?} previous_total={}", spend.month, spend.total);
+            *spend = MonthlySpend { month, ..MonthlySpend::default() };
+        }
+        f(&mut spend)
+    }
+
+    /// This month's spend overall, and by `user_id`.
+    fn spent(&self, user_id: UserId) -> (f64, f64) {
+        self.with_current(|spend| (spend.total, spend.users.get(&user_id.0).copied().unwrap_or(0.0)))
+    }
+
+    fn record(&self, user_id: UserId, cost: f64) {
+        let snapshot = self.with_current(|spend| {
+            spend.total += cost;
+            *spend.users.entry(user_id.0).or_default() += cost;
+            spend.clone()
+        });
+        if let Err(e) = save_json(self.path.as_path(), &snapshot) {
+            log::error!("Failed to save the spend to {}. Continuing. Error: {e:?}", self.path.display());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    /// Only present for legacy completions.
+    text: Option<String>,
+    /// Only present for chat completions.
+    message: Option<CompletionMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionMessage {
+    /// Missing when the model calls tools instead of replying.
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCall {
+    id: String,
+    function: ToolCallFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCallFunction {
+    name: String,
+    /// JSON encoded, and not guaranteed to be valid.
+    arguments: String,
+}
+
+/// A function the model may call instead of replying. Its result is sent back for the model to
+/// reply with.
+struct Tool {
+    description: &'static str,
+    /// JSON schema of the arguments.
+    parameters: serde_json::Value,
+    /// Takes the parsed arguments. Errors are sent back to the model, which may try again.
+    call: Box<ToolFn>,
+}
+
+type ToolFn = dyn Fn(&serde_json::Value) -> Result<String, String> + Send + Sync;
+
+fn default_tools() -> HashMap<String, Tool> {
+    let mut tools = HashMap::new();
+    tools.insert("calculate".to_owned(), Tool {
+        description: "Evaluates an arithmetic expression. Supports +, -, *, /, ^ and parentheses.",
+        parameters: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "The expression to evaluate, like `(2 + 3) * 4`.",
+                },
+            },
+            "required": ["expression"],
+        }),
+        call: Box::new(|arguments| {
+            let expression = arguments["expression"].as_str().ok_or("`expression` should be a string.")?;
+            evaluate_arithmetic(expression).map(|value| value.to_string())
+        }),
+    });
+    tools
+}
+
+/// Describes `tools` as the chat completion's `tools` parameter, sorted by name.
+fn tool_definitions(tools: &HashMap<String, Tool>) -> serde_json::Value {
+    let mut names: Vec<_> = tools.keys().collect();
+    names.sort_unstable();
+    names.into_iter().map(|name| {
+        let tool = &tools[name];
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": tool.description,
+                "parameters": tool.parameters,
+            },
+        })
+    }).collect()
+}
+
+/// The tool calls the first choice of a chat completion asked for, along with its message so that it
+/// can be sent back. `None` if it replied normally.
+fn requested_tool_calls(outcome: &serde_json::Value) -> Option<(serde_json::Value, Vec<ToolCall>)> {
+    let message = outcome.get("choices")?.get(0)?.get("message")?;
+    let tool_calls: Vec<ToolCall> = serde_json::from_value(message.get("tool_calls")?.clone()).map_err(|e| {
+        log::error!("Tool calls could not be parsed. Error: {e:?}");
+    }).ok()?;
+    if tool_calls.is_empty() {
+        return None;
+    }
+    Some((message.clone(), tool_calls))
+}
+
+/// Evaluates `+`, `-`, `*`, `/` and `^` over numbers, with parentheses. `^` binds tightest and is
+/// right associative.
+fn evaluate_arithmetic(expression: &str) -> Result<f64, String> {
+    struct Parser<'a> {
+        chars: std::iter::Peekable<std::str::Chars<'a>>,
+    }
+
+    impl Parser<'_> {
+        fn peek(&mut self) -> Option<char> {
+            while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+            self.chars.peek().copied()
+        }
+
+        fn sum(&mut self) -> Result<f64, String> {
+            let mut value = self.product()?;
+            loop {
+                match self.peek() {
+                    Some('+') => { self.chars.next(); value += self.product()?; },
+                    Some('-') => { self.chars.next(); value -= self.product()?; },
+                    _ => return Ok(value),
+                }
+            }
+        }
+
+        fn product(&mut self) -> Result<f64, String> {
+            let mut value = self.power()?;
+            loop {
+                match self.peek() {
+                    Some('*') => { self.chars.next(); value *= self.power()?; },
+                    Some('/') => { self.chars.next(); value /= self.power()?; },
+                    _ => return Ok(value),
+                }
+            }
+        }
+
+        fn power(&mut self) -> Result<f64, String> {
+            let base = self.unary()?;
+            if self.peek() == Some('^') {
+                self.chars.next();
+                return Ok(base.powf(self.power()?));
+            }
+            Ok(base)
+        }
+
+        fn unary(&mut self) -> Result<f64, String> {
+            match self.peek() {
+                Some('-') => { self.chars.next(); Ok(-self.unary()?) },
+                Some('+') => { self.chars.next(); self.unary() },
+                Some('(') => {
+                    self.chars.next();
+                    let value = self.sum()?;
+                    match self.peek() {
+                        Some(')') => { self.chars.next(); Ok(value) },
+                        _ => Err("Missing a closing parenthesis.".to_owned()),
+                    }
+                },
+                Some(c) if c.is_ascii_digit() || c == '.' => {
+                    let mut number = String::new();
+                    while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                        number.push(c);
+                    }
+                    number.parse().map_err(|_| format!("`{number}` isn't a number."))
+                },
+                Some(c) => Err(format!("Unexpected `{c}`.")),
+                None => Err("The expression ended early.".to_owned()),
+            }
+        }
+    }
+
+    let mut parser = Parser { chars: expression.chars().peekable() };
+    let value = parser.sum()?;
+    match parser.peek() {
+        Some(c) => Err(format!("Unexpected `{c}`.")),
+        None if value.is_finite() => Ok(value),
+        None => Err("The result isn't a finite number.".to_owned()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiErrorResponse {
+    error: OpenAiError,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiError {
+    message: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    code: Option<String>,
+}
+
+impl OpenAiError {
+    /// What to tell the user. Well known codes get advice on what to do about them.
+    fn user_message(&self) -> String {
+        match self.code.as_deref() {
+            Some("content_policy_violation") => "OpenAI rejected that prompt for violating its content policy.".into(),
+            Some("context_length_exceeded") => "That conversation is too long for the model. Try clearing your chat history, or asking for fewer tokens.".into(),
+            Some("insufficient_quota") => "The bot has run out of OpenAI credits. Let whoever runs it know.".into(),
+            Some("invalid_api_key") => "The bot's OpenAI API key isn't valid. Let whoever runs it know.".into(),
+            _ => format!("OpenAI returned an error: {}", self.message),
+        }
+    }
+}
+
+/// Turns an OpenAI error body into the message shown to the user.
+fn parse_error(outcome: serde_json::Value) -> BotError {
+    match serde_json::from_value::<OpenAiErrorResponse>(outcome) {
+        Ok(OpenAiErrorResponse { error }) => {
+            log::error!("OpenAI returned an error. Type: {:?} Code: {:?} Message: {:?}", error.kind, error.code, error.message);
+            BotError::OpenAi(error.user_message())
+        },
+        Err(e) => {
+            log::error!("OpenAI returned an error that could not be parsed. Error: {e:?}");
+            BotError::OpenAi("OpenAI returned an error.".into())
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    categories: HashMap<String, bool>,
+}
+
+impl ModerationResult {
+    /// Names of the categories this was flagged for, sorted.
+    fn flagged_categories(&self) -> Vec<&str> {
+        let mut categories: Vec<_> = self.categories.iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.as_str())
+            .collect();
+        categories.sort_unstable();
+        categories
+    }
+}
+
+/// Model used to embed pinned messages and the prompts they're matched against.
+const EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// Most pinned messages sent along with a prompt.
+const MAX_PINNED_CONTEXT: usize = 3;
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    /// Position of the input this is the embedding of.
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Zero if either vector is all zeroes.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// The pins most similar to `prompt_embedding`, most similar first, as text to add to the system
+/// prompt.
+fn pinned_context(pins: &[PinnedMessage], prompt_embedding: &[f32]) -> String {
+    let mut ranked: Vec<_> = pins.iter()
+        .map(|pin| (cosine_similarity(pin.embedding.as_slice(), prompt_embedding), pin))
+        .collect();
+    ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    let relevant = ranked.into_iter()
+        .take(MAX_PINNED_CONTEXT)
+        .map(|(_, pin)| format!("- {}", pin.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("Pinned messages in this channel that may be relevant:\n{relevant}")
+}
+
+/// Sizes DALL-E can generate, as accepted by the `size` parameter.
+const IMAGE_SIZES: &[&str] = &["256x256", "512x512", "1024x1024"];
+
+#[derive(Debug, Deserialize)]
+struct ImageResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    url: String,
+}
+
+fn build_image_generation(prompt: &str, size: &str) -> serde_json::Value {
+    serde_json::json!({
+        "prompt": prompt,
+        "size": size,
+        "n": 1,
+    })
+}
+
+/// File extensions Whisper can transcribe.
+const AUDIO_EXTENSIONS: &[&str] = &["flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "oga", "ogg", "wav", "webm"];
+
+/// Image formats vision models accept.
+const IMAGE_EXTENSIONS: &[&str] = &["gif", "jpeg", "jpg", "png", "webp"];
+
+/// Whether `attachment` is an image a vision model can see, going by its file extension.
+fn is_image_attachment(attachment: &Attachment) -> bool {
+    let extension = attachment.filename.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+    extension.is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// The first attachment of `msg` that Whisper can transcribe, going by its file extension.
+fn audio_attachment(msg: &Message) -> Option<&Attachment> {
+    msg.attachments.iter().find(|attachment| {
+        let extension = attachment.filename.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+        extension.is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension.as_str()))
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+/// A single server-sent event from a streamed completion.
+#[derive(Debug, Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChunkChoice>,
+    /// Only present in the last event, which has no choices.
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChunkChoice {
+    /// Only present for legacy completions.
+    text: Option<String>,
+    /// Only present for chat completions.
+    delta: Option<CompletionDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionDelta {
+    content: Option<String>,
+}
+
+/// Where a streamed reply's partial text goes, and how to stop it early.
+struct StreamTarget {
+    progress: watch::Sender<String>,
+    cancel: CancellationToken,
+}
+
+/// Reads a streamed completion to the end, publishing the text accumulated so far to `progress`
+/// after every event. If cancelled, stops reading and returns what's been read so far. Usage is only
+/// known if the stream got as far as its last event.
+async fn read_completion_stream(mut response: reqwest::Response, target: &StreamTarget) -> Result<(String, Option<TokenUsage>), BotError> {
+    let mut buffer = vec![];
+    let mut text = String::new();
+    let mut usage = None;
+    loop {
+        let chunk = tokio::select! {
+            chunk = response.chunk() => chunk,
+            () = target.cancel.cancelled() => {
+                log::info!("Completion stream was cancelled. Keeping what was read so far.");
+                return Ok((text, usage));
+            },
+        };
+        let bytes = match chunk {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => {
+                log::warn!("Completion stream ended without `[DONE]`.");
+                return Ok((text, usage));
+            },
+            Err(e) if e.is_timeout() => {
+                log::error!("Completion stream timed out. Error: {e:?}");
+                return Err(BotError::OpenAi("The AI took too long to respond, try again in a bit.".into()));
+            },
+            Err(e) => {
+                log::error!("Completion stream failed due to {e:?}");
+                return Err(BotError::OpenAi("The response from OpenAI was cut off.".into()));
+            },
+        };
+        buffer.extend_from_slice(&bytes);
+
+        // Events are newline delimited, so anything before the last newline is complete.
+        while let Some(newline) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok((text, usage));
+            }
+
+            let chunk: CompletionChunk = match serde_json::from_str(data) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    log::warn!("Completion stream event could not be parsed. Skipping. Event: {data:?} Error: {e:?}");
+                    continue;
+                },
+            };
+            if chunk.usage.is_some() {
+                usage = chunk.usage;
+            }
+            let delta = chunk.choices.into_iter().next()
+                .and_then(|choice| choice.delta.and_then(|delta| delta.content).or(choice.text));
+            if let Some(delta) = delta {
+                text.push_str(delta.as_str());
+                target.progress.send_replace(text.clone());
+            }
+        }
+    }
+}
+
+/// Pulls the text of the first choice, and the usage if reported, out of a (legacy or chat)
+/// completion response.
+fn parse_completion(outcome: serde_json::Value) -> Result<(Vec<String>, Option<TokenUsage>), BotError> {
+    if outcome.get("error").is_some() {
+        return Err(parse_error(outcome));
+    }
+
+    let response: CompletionResponse = serde_json::from_value(outcome).map_err(|e| {
+        log::error!("Completion response could not be parsed. Error: {e:?}");
+        BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into())
+    })?;
+    log::info!("Completion system_fingerprint={:?}", response.system_fingerprint);
+    let choices: Vec<_> = response.choices.into_iter()
+        .filter_map(|choice| match choice {
+            CompletionChoice { message: Some(CompletionMessage { content: Some(content), .. }), .. } => Some(content),
+            CompletionChoice { text: Some(text), .. } => Some(text),
+            _ => {
+                log::warn!("Completion choice had neither text nor a message. Skipping it.");
+                None
+            },
+        })
+        .collect();
+    if choices.is_empty() {
+        log::error!("Completion response had no usable choices.");
+        return Err(BotError::OpenAi("OpenAI sent back an empty response.".into()));
+    }
+    Ok((choices, response.usage))
+}
+
+/// How likely the model found a token of its reply, and the alternatives it found likeliest.
+#[derive(Debug, Clone)]
+struct TokenLogprobs {
+    token: String,
+    logprob: f64,
+    /// Usually includes `token` itself.
+    top: Vec<(String, f64)>,
+}
+
+/// `logprobs` of a chat completion choice.
+#[derive(Debug, Deserialize)]
+struct ChatLogprobs {
+    content: Option<Vec<ChatTokenLogprobs>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatTokenLogprobs {
+    token: String,
+    logprob: f64,
+    #[serde(default)]
+    top_logprobs: Vec<ChatTopLogprob>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatTopLogprob {
+    token: String,
+    logprob: f64,
+}
+
+/// `logprobs` of a legacy completion choice, which lists tokens and their logprobs side by side.
+#[derive(Debug, Deserialize)]
+struct LegacyLogprobs {
+    tokens: Vec<String>,
+    token_logprobs: Vec<Option<f64>>,
+    #[serde(default)]
+    top_logprobs: Vec<Option<HashMap<String, f64>>>,
+}
+
+/// Logprobs of the first choice in a completion response, if it has any. Their shape differs between
+/// chat and legacy completions, and anything unexpected is logged and skipped rather than failing
+/// the reply.
+fn first_choice_logprobs(outcome: &serde_json::Value) -> Option<Vec<TokenLogprobs>> {
+    let logprobs = outcome["choices"].get(0)?.get("logprobs").filter(|logprobs| !logprobs.is_null())?;
+    let parsed = if logprobs.get("tokens").is_some() {
+        serde_json::from_value::<LegacyLogprobs>(logprobs.clone()).map(|legacy| {
+            let mut top = legacy.top_logprobs.into_iter();
+            legacy.tokens.into_iter().zip(legacy.token_logprobs).map(|(token, logprob)| {
+                let mut alternatives: Vec<_> = top.next().flatten().unwrap_or_default().into_iter().collect();
+                alternatives.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+                TokenLogprobs { token, logprob: logprob.unwrap_or(f64::NEG_INFINITY), top: alternatives }
+            }).collect()
+        })
+    } else {
+        serde_json::from_value::<ChatLogprobs>(logprobs.clone()).map(|chat| {
+            chat.content.unwrap_or_default().into_iter().map(|position| TokenLogprobs {
+                token: position.token,
+                logprob: position.logprob,
+                top: position.top_logprobs.into_iter().map(|top| (top.token, top.logprob)).collect(),
+            }).collect()
+        })
+    };
+    match parsed {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            log::warn!("Logprobs could not be parsed. Skipping them. Error: {e:?}");
+            None
+        },
+    }
+}
+
+/// How many of the reply's first tokens have their logprobs shown.
+const LOGPROB_POSITIONS: usize = 8;
+
+/// Shows the first few tokens of a reply with how likely each was, followed by the alternatives.
+fn render_logprobs(logprobs: &[TokenLogprobs]) -> String {
+    // Backticks would end the code spans the tokens are shown in.
+    let show = |token: &str, logprob: f64| format!("`{:?}` {:.1}%", token.replace('`', "'"), logprob.exp() * 100.0);
+    logprobs.iter().take(LOGPROB_POSITIONS).map(|position| {
+        let alternatives = position.top.iter().map(|(token, logprob)| show(token, *logprob)).collect::<Vec<_>>().join(", ");
+        if alternatives.is_empty() {
+            show(position.token.as_str(), position.logprob)
+        } else {
+            format!("{} | {alternatives}", show(position.token.as_str(), position.logprob))
+        }
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// Sent in place of a response that's empty or only whitespace.
+const EMPTY_RESPONSE_PLACEHOLDER: &str = "(the model returned an empty response)";
+
+/// What every chat request is answered with when [`Config::dry_run`] is set.
+const DRY_RUN_RESPONSE: &str = "(dry run, OpenAI was not called)";
+
+/// Discord rejects messages with content longer than this many characters.
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+/// Marks the start and end of a code block in Discord markdown.
+const CODE_FENCE: &str = "```";
+
+/// Most characters of a code block's language hint that are carried over when it's reopened.
+const CODE_FENCE_INFO_LIMIT: usize = 32;
+
+/// Splits `content` into pieces that each fit in a single Discord message.
+fn chunk_message(content: &str) -> Vec<String> {
+    chunk_text(content, DISCORD_MESSAGE_LIMIT)
+}
+
+/// Splits `content` into pieces of at most `max_chars` characters. Prefers to break at the last
+/// newline, then the last whitespace, before the limit. The character broken on is dropped.
+///
+/// A code block that's split is closed at the end of its chunk and reopened, with the same
+/// language hint, at the start of the next. A code block that's never closed is closed anyway.
+fn chunk_text(content: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut rest = content;
+    let mut reopen = String::new();
+    while !rest.is_empty() {
+        // Leave room for the fence that closes a code block split by this chunk.
+        let limit = max_chars.saturating_sub(reopen.chars().count() + CODE_FENCE.len() + 1).max(1);
+        let (piece, next) = split_chunk(rest, limit);
+        let mut chunk = std::mem::take(&mut reopen) + piece;
+        if let Some(info) = unclosed_code_fence(chunk.as_str()) {
+            reopen = format!("{CODE_FENCE}{info}\n");
+            chunk.push('\n');
+            chunk.push_str(CODE_FENCE);
+        }
+        chunks.push(chunk);
+        rest = next;
+    }
+    chunks
+}
+
+/// Splits off as much of `content` as fits in `limit` characters, returning it and the rest.
+fn split_chunk(content: &str, limit: usize) -> (&str, &str) {
+    let Some((hard_end, _)) = content.char_indices().nth(limit) else {
+        return (content, "");
+    };
+    let window = &content[..hard_end];
+    let soft_break = window.char_indices().rev().find(|(_, c)| *c == '\n')
+        .or_else(|| window.char_indices().rev().find(|(_, c)| c.is_whitespace()))
+        .filter(|(idx, _)| *idx > 0);
+    match soft_break {
+        Some((idx, c)) => (&content[..idx], &content[idx + c.len_utf8()..]),
+        None => (window, &content[hard_end..]),
+    }
+}
+
+/// The language hint of the code block left open at the end of `text`, if there is one. Blocks
+/// without a hint give an empty string.
+fn unclosed_code_fence(text: &str) -> Option<String> {
+    let mut open = None;
+    for line in text.lines() {
+        let Some(info) = line.trim_start().strip_prefix(CODE_FENCE) else {
+            continue;
+        };
+        open = match open {
+            Some(_) => None,
+            None => Some(info.split_whitespace().next().unwrap_or("").chars().take(CODE_FENCE_INFO_LIMIT).collect()),
+        };
+    }
+    open
+}
+
+/// Characters of the replies to pick from that fit in one message, shared between them.
+const MAX_CHOICE_CHARS: usize = 5000;
+
+/// Discord's limit on an embed's description.
+const MAX_EMBED_DESCRIPTION_CHARS: usize = 4096;
+
+/// Discord's limit on the text of all of a message's embeds put together.
+const MAX_EMBED_TOTAL_CHARS: usize = 6000;
+
+/// Cuts `text` down to `max_chars` characters, marking where it was cut.
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// What [`Handler::undo`] took back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Undone {
+    Exchange,
+    Summary,
+}
+
+/// Discord won't take a longer text input in a modal.
+const COMPOSE_PROMPT_LIMIT: u64 = 4000;
+
+/// The `custom_id` of the compose modal's text input.
+const COMPOSE_PROMPT_ID: &str = "prompt";
+
+/// The `custom_id` of the compose modal, which carries the model picked for it, if any.
+fn compose_modal_id(model: Option<&str>) -> String {
+    match model {
+        Some(model) => format!("compose:{model}"),
+        None => "compose".to_owned(),
+    }
+}
+
+/// The model carried by a compose modal's `custom_id`, or `None` if it isn't one.
+fn parse_compose_modal_id(custom_id: &str) -> Option<Option<&str>> {
+    match custom_id.strip_prefix("compose")? {
+        "" => Some(None),
+        rest => rest.strip_prefix(':').map(Some),
+    }
+}
+
+fn quote(text: &str) -> String {
+    text.lines().map(|line| format!("> {line}")).collect::<Vec<_>>().join("\n")
+}
+
+fn persona_reply(persona: Option<&str>, reset: bool) -> String {
+    match (persona, reset) {
+        (Some(persona), false) => format!("Updated the persona for this conversation, which carries on as is. From now on:\n{}", quote(persona)),
+        (Some(persona), true) => format!("Put the default persona back for this conversation:\n{}", quote(persona)),
+        (None, _) => "Put the default persona back for this conversation, which is to have none.".to_owned(),
+    }
+}
+
+fn set_model_reply(model: &str) -> String {
+    format!("Now using `{model}` for the rest of this conversation, unless a chat names another model.")
+}
+
+fn undo_reply(undone: Option<Undone>, locale: Locale) -> &'static str {
+    let text = match undone {
+        Some(Undone::Exchange) => Text::UndidExchange,
+        Some(Undone::Summary) => Text::UndidSummary,
+        None => Text::NothingToUndo,
+    };
+    text.get(locale)
+}
+
+/// Sent as the prompt when summarizing, after the history being summarized.
+const SUMMARY_INSTRUCTION: &str = "Summarize the conversation so far as concisely as you can, keeping every fact, decision and open question needed to carry on with it.";
+
+const NOTHING_TO_SUMMARIZE: &str = "There's nothing to summarize yet. Send a prompt first.";
+
+fn export_attachment(export: String) -> AttachmentType<'static> {
+    AttachmentType::Bytes { data: Cow::Owned(export.into_bytes()), filename: "transcript.md".to_owned() }
+}
+
+fn pins_loaded_reply(count: usize) -> String {
+    match count {
+        0 => "There are no pinned messages here with any text.".to_owned(),
+        1 => "Loaded 1 pinned message. It'll be sent along with your next prompt.".to_owned(),
+        count => format!("Loaded {count} pinned messages. Up to {MAX_PINNED_CONTEXT} of the most relevant will be sent along with your next prompt."),
+    }
+}
+
+/// Makes sure only the user whose history would be cleared answers whether to clear it.
+fn check_clear_answerer(asked: UserId, answerer: UserId) -> Result<(), BotError> {
+    if asked == answerer {
+        return Ok(());
+    }
+    log::warn!("User {answerer:?} tried to answer whether to clear {asked:?}'s history.");
+    Err(BotError::UserInput("Only whoever asked to clear their history can answer this.".into()))
+}
+
+/// Quotes the prompt on its own lines, followed by the model's response.
+fn format_reply(prompt: &str, response: &str) -> String {
+    let response = response.trim();
+    format!("{}\n{response}", quote(prompt))
+}
+
+/// How a chat reply's response is laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ReplyFormat {
+    /// As the model wrote it.
+    #[default]
+    Plain,
+    /// In embeds, with the model's name as their footer.
+    Embed,
+    /// In a code block.
+    Code,
+}
+
+impl ReplyFormat {
+    const ALL: [Self; 3] = [Self::Plain, Self::Embed, Self::Code];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Plain => "plain",
+            Self::Embed => "embed",
+            Self::Code => "code",
+        }
+    }
+}
+
+fn validate_reply_format(format: &str) -> Result<ReplyFormat, BotError> {
+    if let Some(format) = ReplyFormat::ALL.into_iter().find(|known| known.as_str() == format) {
+        return Ok(format);
+    }
+    log::warn!("Unknown reply format `{format}`.");
+    let known = ReplyFormat::ALL.map(|known| format!("`{}`", known.as_str())).join(", ");
+    Err(BotError::UserInput(format!("Format should be one of {known}. Found `{format}`.")))
+}
+
+/// Puts `text` in a code block. Backticks in it are broken up with zero width spaces whenever it has
+/// fences of its own, so that those can't close the block early.
+fn code_block(text: &str) -> String {
+    let text = text.trim();
+    let text = if text.contains(CODE_FENCE) { Cow::Owned(text.replace('`', "`\u{200b}")) } else { Cow::Borrowed(text) };
+    format!("{CODE_FENCE}\n{text}\n{CODE_FENCE}")
+}
+
+/// Puts `note` under `reply`, set apart from it.
+fn add_note(reply: String, note: Option<&str>) -> String {
+    match note {
+        Some(note) => format!("{reply}\n\n*{note}*"),
+        None => reply,
+    }
+}
+
+/// Says that `replied` answered in place of `model`, if it did.
+fn fallback_note(model: &str, replied: &str, locale: Locale) -> Option<String> {
+    (model != replied).then(|| Text::FallbackUsed.get(locale).replace("{fallback}", replied).replace("{model}", model))
+}
+
+/// One message's worth of a reply.
+#[derive(Debug, Clone, Default)]
+struct ReplyChunk {
+    content: String,
+    /// Description and footer of an embed sent under the content.
+    embed: Option<(String, String)>,
+}
+
+impl ReplyChunk {
+    fn embeds(&self) -> Vec<CreateEmbed> {
+        self.embed.iter().map(|(description, footer)| {
+            let mut embed = CreateEmbed::default();
+            embed.description(description).footer(|embed_footer| embed_footer.text(footer));
+            embed
+        }).collect()
+    }
+
+    /// Characters of the embed, which count towards [`MAX_EMBED_TOTAL_CHARS`].
+    fn embed_chars(&self) -> usize {
+        self.embed.as_ref().map_or(0, |(description, footer)| description.chars().count() + footer.chars().count())
+    }
+}
+
+/// Splits `content` into chunks without embeds.
+fn text_chunks(content: &str) -> Vec<ReplyChunk> {
+    chunk_message(content).into_iter().map(|content| ReplyChunk { content, embed: None }).collect()
+}
+
+/// Quotes the prompt, followed by the response laid out in `format`, split into as many messages as
+/// that takes. Embed replies quote the prompt in the first message and put a piece of the response
+/// in each message's embed, with `model` as its footer. A `note` goes after the response, or in place
+/// of the footer.
+fn format_reply_chunks(format: ReplyFormat, prompt: &str, response: &str, model: &str, note: Option<&str>) -> Vec<ReplyChunk> {
+    let with_note = |reply: String| add_note(reply, note);
+    match format {
+        ReplyFormat::Plain => text_chunks(with_note(format_reply(prompt, response)).as_str()),
+        ReplyFormat::Code => text_chunks(with_note(format_reply(prompt, code_block(response).as_str())).as_str()),
+        ReplyFormat::Embed => {
+            let mut descriptions = chunk_text(response.trim(), MAX_EMBED_DESCRIPTION_CHARS);
+            if descriptions.is_empty() {
+                descriptions.push(EMPTY_RESPONSE_PLACEHOLDER.to_owned());
+            }
+            // A full description leaves this much of the total for the footer.
+            let footer = truncate_chars(note.unwrap_or(model), MAX_EMBED_TOTAL_CHARS - MAX_EMBED_DESCRIPTION_CHARS);
+            let mut content = truncate_chars(quote(prompt).as_str(), DISCORD_MESSAGE_LIMIT);
+            descriptions.into_iter()
+                .map(|description| ReplyChunk { content: std::mem::take(&mut content), embed: Some((description, footer.clone())) })
+                .collect()
+        },
+    }
+}
+
+/// Undoes [`format_reply`], splitting a reply back into the prompt and response. Returns `None` for
+/// messages that don't start with a quoted prompt, like errors.
+fn parse_reply(content: &str) -> Option<(String, &str)> {
+    let mut prompt_lines = Vec::new();
+    let mut rest = content;
+    while let Some(line) = rest.strip_prefix("> ") {
+        let (line, after) = line.split_once('\n').unwrap_or((line, ""));
+        prompt_lines.push(line);
+        rest = after;
+    }
+    (!prompt_lines.is_empty()).then(|| (prompt_lines.join("\n"), rest.trim()))
+}
+
+/// What `msg` replies to, fetching it if Discord didn't send it along.
+async fn referenced_message(ctx: &Context, msg: &Message) -> Option<Message> {
+    match (msg.referenced_message.as_deref(), msg.message_reference.as_ref()) {
+        (Some(referenced_message), _) => Some(referenced_message.clone()),
+        (None, Some(reference)) => match reference.message_id {
+            Some(message_id) => reference.channel_id.message(ctx, message_id).await.map_err(|e| {
+                log::warn!("Failed to fetch referenced message. Ignoring it. Error: {e:?}");
+            }).ok(),
+            None => None,
+        },
+        (None, None) => None,
+    }
+}
+
+/// Rebuilds the conversation that led up to `reply`, one of the bot's chat replies, by walking up
+/// the messages it replies to. Each of the bot's replies quotes the prompt it answers, so only
+/// those are read, and the walk ends at the first message that isn't one. At most `max_depth`
+/// messages are looked at.
+async fn reply_chain(ctx: &Context, reply: &Message, max_depth: usize) -> Option<Vec<ChatTurn>> {
+    let bot_id = ctx.cache.current_user_id();
+    let mut turns = Vec::new();
+    let mut current = Some(reply.clone());
+    let mut depth = 0;
+    while let Some(message) = current.take().filter(|_| depth < max_depth) {
+        if message.author.id != bot_id {
+            // A prompt. Its text is already quoted in the reply to it, so only follow it up.
+            depth += 1;
+            current = referenced_message(ctx, &message).await;
+            continue;
+        }
+        let Some((prompt, response)) = parse_reply(message.content.as_str()) else {
+            break;
+        };
+        // Replies in embeds only quote the prompt in their content.
+        let response = match message.embeds.first().and_then(|embed| embed.description.as_deref()) {
+            Some(description) if response.is_empty() => description,
+            _ => response,
+        };
+        depth += 1;
+        let at = *message.timestamp;
+        let prompter = referenced_message(ctx, &message).await;
+        let user_name = prompter.as_ref().map_or_else(|| "user".to_owned(), |prompter| prompter.author.name.clone());
+        turns.push(ChatTurn { role: Role::Assistant, name: message.author.name.clone(), content: response.to_owned(), at });
+        turns.push(ChatTurn { role: Role::User, name: user_name, content: prompt, at });
+        current = prompter;
+    }
+    if turns.is_empty() {
+        return None;
+    }
+    turns.reverse();
+    log::info!("REPLY-CHAIN turns={} depth={depth}", turns.len());
+    Some(turns)
+}
+
+/// What a button the bot sent does, as encoded in its `custom_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ComponentAction {
+    /// Stops the reply streaming in response to the message with this id.
+    Stop(MessageId),
+    /// Asks whether to clear the clicker's chat history.
+    Clear,
+    /// Clears the chat history of the user with this id, who has to be the one clicking.
+    ConfirmClear(UserId),
+    /// Dismisses the question of whether to clear the chat history of the user with this id.
+    CancelClear(UserId),
+    /// Re-runs the clicker's last request.
+    Regenerate,
+    /// Keeps the reply the clicker picked out of several.
+    Pick,
+    /// Shows the next part of a long reply.
+    Continue,
+    /// Opens the prompt that failed with this error in the compose modal, to be fixed and sent again.
+    EditResend,
+}
+
+impl ComponentAction {
+    fn custom_id(self) -> String {
+        match self {
+            Self::Stop(message_id) => format!("stop:{}", message_id.0),
+            Self::Clear => "clear".to_owned(),
+            Self::ConfirmClear(user_id) => format!("clear:confirm:{}", user_id.0),
+            Self::CancelClear(user_id) => format!("clear:cancel:{}", user_id.0),
+            Self::Regenerate => "regenerate".to_owned(),
+            Self::Pick => "pick".to_owned(),
+            Self::Continue => "continue".to_owned(),
+            Self::EditResend => "resend".to_owned(),
+        }
+    }
+
+    fn parse(custom_id: &str) -> Option<Self> {
+        match custom_id {
+            "clear" => Some(Self::Clear),
+            "regenerate" => Some(Self::Regenerate),
+            "pick" => Some(Self::Pick),
+            "continue" => Some(Self::Continue),
+            "resend" => Some(Self::EditResend),
+            _ => {
+                let (kind, id) = custom_id.rsplit_once(':')?;
+                let id = id.parse().ok()?;
+                match kind {
+                    "stop" => Some(Self::Stop(MessageId(id))),
+                    "clear:confirm" => Some(Self::ConfirmClear(UserId(id))),
+                    "clear:cancel" => Some(Self::CancelClear(UserId(id))),
+                    _ => None,
+                }
+            },
+        }
+    }
+}
+
+/// How long the question of whether to clear chat history can be answered.
+const CLEAR_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Adds the buttons that answer whether to clear `user_id`'s chat history.
+fn add_clear_confirmation_buttons(components: &mut CreateComponents, user_id: UserId, locale: Locale) -> &mut CreateComponents {
+    components.create_action_row(|row| {
+        row
+            .create_button(|button| button.custom_id(ComponentAction::ConfirmClear(user_id).custom_id()).label(Text::ConfirmClearButton.get(locale)).style(ButtonStyle::Danger))
+            .create_button(|button| button.custom_id(ComponentAction::CancelClear(user_id).custom_id()).label(Text::CancelClearButton.get(locale)).style(ButtonStyle::Secondary))
+    })
+}
+
+/// Adds the buttons that go under the last message of a reply.
+fn add_reply_buttons(components: &mut CreateComponents, locale: Locale) -> &mut CreateComponents {
+    components.create_action_row(|row| {
+        row
+            .create_button(|button| button.custom_id(ComponentAction::Regenerate.custom_id()).label(Text::RegenerateButton.get(locale)).style(ButtonStyle::Secondary))
+            .create_button(|button| button.custom_id(ComponentAction::Clear.custom_id()).label(Text::ClearHistoryButton.get(locale)).style(ButtonStyle::Secondary))
+    })
+}
+
+/// How long the rest of a long reply can still be shown with its continue button.
+const CONTINUATION_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Splits a plain text reply into the part sent right away and the parts held back until asked for.
+fn split_reply(content: &str) -> (ReplyChunk, Vec<ReplyChunk>) {
+    split_chunks(text_chunks(content))
+}
+
+/// Splits `chunks` into the one sent right away and the ones held back until asked for.
+fn split_chunks(chunks: Vec<ReplyChunk>) -> (ReplyChunk, Vec<ReplyChunk>) {
+    let mut chunks = chunks.into_iter();
+    let first = chunks.next().unwrap_or_default();
+    (first, chunks.collect())
+}
+
+/// Adds a continue button if there's `more` of the reply to show, otherwise the reply buttons.
+fn add_chunk_buttons(components: &mut CreateComponents, more: bool, locale: Locale) -> &mut CreateComponents {
+    if !more {
+        return add_reply_buttons(components, locale);
+    }
+    components.create_action_row(|row| {
+        row.create_button(|button| button.custom_id(ComponentAction::Continue.custom_id()).label(Text::ContinueButton.get(locale)).style(ButtonStyle::Primary))
+    })
+}
+
+/// Adds the button that opens the failed prompt in the compose modal.
+fn add_resend_button(components: &mut CreateComponents, locale: Locale) -> &mut CreateComponents {
+    components.create_action_row(|row| {
+        row.create_button(|button| button.custom_id(ComponentAction::EditResend.custom_id()).label(Text::EditResendButton.get(locale)).style(ButtonStyle::Secondary))
+    })
+}
+
+/// How long a failed prompt can be resent.
+const FAILED_PROMPT_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// A chat prompt that ended in an error, kept for the error's resend button.
+#[derive(Debug, Clone)]
+struct FailedPrompt {
+    failed_at: Instant,
+    /// Only they can resend it.
+    user_id: UserId,
+    model: Option<String>,
+    prompt: String,
+}
+
+impl FailedPrompt {
+    /// `None` if there's no prompt to speak of, like when a voice message stood in for it.
+    fn new(user_id: UserId, model: Option<&str>, prompt: &str) -> Option<Self> {
+        let prompt = prompt.trim();
+        if prompt.is_empty() {
+            return None;
+        }
+        Some(Self { failed_at: Instant::now(), user_id, model: model.map(str::to_owned), prompt: prompt.to_owned() })
+    }
+}
+
+/// Options the classic chat command takes before the prompt.
+const CLASSIC_CHAT_OPTIONS: [&str; 9] = ["max_tokens", "thread", "stop", "seed", "top_p", "language", "format", "json", "fallback"];
+
+/// The model and prompt of a classic chat command's `args`. Options are left out, since the compose
+/// modal doesn't take them.
+fn classic_chat_prompt(args: &str) -> (Option<&str>, &str) {
+    let (model, mut prompt) = split_classic_model(args);
+    while let Some((_, _, rest)) = split_classic_option(prompt).filter(|(name, ..)| CLASSIC_CHAT_OPTIONS.contains(name)) {
+        prompt = rest;
+    }
+    (model, prompt)
+}
+
+/// The prompt written in a compose modal, or an empty one if it's missing.
+fn modal_prompt(submission: &ModalSubmitInteraction) -> &str {
+    submission.data.components.iter()
+        .flat_map(|row| row.components.iter())
+        .find_map(|component| match component {
+            ActionRowComponent::InputText(input) if input.custom_id == COMPOSE_PROMPT_ID => Some(input.value.trim()),
+            _ => None,
+        })
+        .unwrap_or("")
+}
+
+/// Fills in the compose modal for `model`, with `prompt` already written in, if there is one.
+fn build_compose_modal<'a, 'b>(data: &'b mut CreateInteractionResponseData<'a>, model: Option<&str>, prompt: Option<&str>) -> &'b mut CreateInteractionResponseData<'a> {
+    data
+        .custom_id(compose_modal_id(model))
+        .title("Chat")
+        .components(|components| components.create_action_row(|row| row.create_input_text(|input| {
+            input
+                .custom_id(COMPOSE_PROMPT_ID)
+                .label("Prompt")
+                .style(InputTextStyle::Paragraph)
+                .max_length(COMPOSE_PROMPT_LIMIT)
+                .required(true);
+            if let Some(prompt) = prompt {
+                input.value(truncate_chars(prompt, COMPOSE_PROMPT_LIMIT as usize));
+            }
+            input
+        })))
+}
+
+/// How often a streamed reply is edited to show the latest text.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Discord shows the typing indicator for about 10 seconds after each broadcast.
+const TYPING_INTERVAL: Duration = Duration::from_secs(8);
+
+/// Drives `fut` to completion while keeping the typing indicator up in `channel_id`. Typing stops
+/// early if a broadcast fails, but `fut` still runs to completion.
+async fn while_typing<F: std::future::Future>(ctx: &Context, channel_id: ChannelId, fut: F) -> F::Output {
+    let typing = async {
+        loop {
+            if let Err(e) = channel_id.broadcast_typing(ctx).await {
+                log::warn!("Failed to broadcast typing. Stopping. Error: {e:?}");
+                break;
+            }
+            tokio::time::sleep(TYPING_INTERVAL).await;
+        }
+    };
+
+    tokio::pin!(fut);
+    tokio::select! {
+        output = &mut fut => return output,
+        () = typing => {},
+    }
+    fut.await
+}
+
+/// First delay between retries, doubled on every subsequent attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Whether a response with `status` means OpenAI can't serve anyone right now, as opposed to just
+/// this request being bad. Counts against the circuit breaker.
+fn is_outage(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN || is_retryable(status)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    /// Requests go through.
+    Closed,
+    /// Requests are refused until the cooldown is over.
+    Open { until: Instant },
+    /// A single request was let through to see whether OpenAI recovered. If it never reports back,
+    /// another is let through after the cooldown.
+    HalfOpen { probe_at: Instant },
+}
+
+/// Stops sending OpenAI requests for a while after too many fail in a row, so that an outage or a
+/// bad key doesn't get hammered.
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: CircuitState,
+    /// Failures in a row, and when the first of them happened.
+    failures: u32,
+    first_failure: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new() -> Self {
+        Self { state: CircuitState::Closed, failures: 0, first_failure: None }
+    }
+
+    /// Whether a request may be sent at `now`.
+    fn allow(&mut self, now: Instant, cooldown: Duration) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open { until } | CircuitState::HalfOpen { probe_at: until } if now >= until => {
+                log::info!("CIRCUIT state=half_open");
+                self.state = CircuitState::HalfOpen { probe_at: now + cooldown };
+                true
+            },
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => false,
+        }
+    }
+
+    /// Records how a request that was allowed at `now` went.
+    fn record(&mut self, succeeded: bool, now: Instant, cfg: &Config) {
+        if succeeded {
+            if self.state != CircuitState::Closed {
+                log::info!("CIRCUIT state=closed");
+            }
+            *self = Self::new();
+            return;
+        }
+
+        if cfg.circuit_failure_threshold == 0 {
+            return;
+        }
+        if matches!(self.state, CircuitState::HalfOpen { .. }) {
+            log::warn!("CIRCUIT state=open reason=probe_failed cooldown={:?}", cfg.circuit_cooldown);
+            self.state = CircuitState::Open { until: now + cfg.circuit_cooldown };
+            return;
+        }
+        let streak_expired = self.first_failure.is_none_or(|first| now.saturating_duration_since(first) > cfg.circuit_window);
+        if streak_expired {
+            self.failures = 0;
+            self.first_failure = Some(now);
+        }
+        self.failures += 1;
+        if self.state == CircuitState::Closed && self.failures >= cfg.circuit_failure_threshold {
+            log::warn!("CIRCUIT state=open reason=failures failures={} cooldown={:?}", self.failures, cfg.circuit_cooldown);
+            self.state = CircuitState::Open { until: now + cfg.circuit_cooldown };
+        }
+    }
+}
+
+/// How long a key OpenAI rejected is skipped for. It's likely revoked, but the others may be too.
+const REJECTED_KEY_BENCH: Duration = Duration::from_secs(10 * 60);
+
+/// Picks which of the configured API keys each request uses, round robin. Keys that were rate
+/// limited or rejected are benched, and skipped until then unless every key is.
+struct KeyPool {
+    next: AtomicUsize,
+    /// Until when each key is benched, by its index in `Config::openai_api_keys`.
+    benched_until: Mutex<Vec<Option<Instant>>>,
+}
+
+impl KeyPool {
+    fn new(count: usize) -> Self {
+        Self { next: AtomicUsize::new(0), benched_until: Mutex::new(vec![None; count]) }
+    }
+
+    /// Index of the next key to use.
+    fn pick(&self, now: Instant) -> usize {
+        let benched_until = self.benched_until.lock();
+        let count = benched_until.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|&key| benched_until[key].is_none_or(|until| now >= until))
+            .unwrap_or(start)
+    }
+
+    /// Whether any key isn't benched.
+    fn has_available(&self, now: Instant) -> bool {
+        self.benched_until.lock().iter().any(|until| until.is_none_or(|until| now >= until))
+    }
+
+    fn bench(&self, key: usize, duration: Duration) {
+        log::warn!("KEY-BENCHED key={key} duration={duration:?}");
+        self.benched_until.lock()[key] = Some(Instant::now() + duration);
+    }
+}
+
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether OpenAI answered that it's too busy, rather than that it's broken.
+fn is_overloaded(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// Reads a `Retry-After` header given in seconds. The HTTP-date form is ignored.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    let secs: f64 = value.to_str().ok()?.trim().parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let exponential = RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+    let jitter = RETRY_BASE_DELAY.mul_f64(rand::random::<f64>());
+    exponential.saturating_add(jitter)
+}
+
+/// Most times a Discord call wrapped in [`with_discord_retries`] is tried.
+const DISCORD_ATTEMPTS: u32 = 3;
+
+/// Why a failed Discord call is worth trying again, or `None` if it isn't. Serenity already waits
+/// out the rate limits Discord announces ahead of time, so a 429 here is one it couldn't predict.
+fn discord_retry_reason(e: &serenity::Error) -> Option<&'static str> {
+    let serenity::Error::Http(e) = e else {
+        return None;
+    };
+    match e.status_code()?.as_u16() {
+        429 => Some("rate_limited"),
+        500..=599 => Some("server_error"),
+        _ => None,
+    }
+}
+
+/// Runs `call` until it succeeds, fails in a way that isn't worth retrying, or runs out of
+/// attempts, backing off in between. `call` is named `what` in the logs.
+async fn with_discord_retries<T, F, Fut>(what: &str, mut call: F) -> serenity::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = serenity::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let e = match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        match discord_retry_reason(&e) {
+            Some(reason) if attempt < DISCORD_ATTEMPTS => {
+                let delay = backoff_delay(attempt);
+                log::warn!("DISCORD-RETRY call={what} reason={reason} attempt={attempt} delay={delay:?} error={e:?}");
+                tokio::time::sleep(delay).await;
+            },
+            Some(reason) => {
+                log::error!("DISCORD-GAVE-UP call={what} reason={reason} attempts={attempt} error={e:?}");
+                return Err(e);
+            },
+            None => {
+                log::error!("DISCORD-FAILED call={what} error={e:?}");
+                return Err(e);
+            },
+        }
+    }
+}
+
+/// How much longer someone who last made a request at `last_request` has to wait as of `now`, if
+/// at all.
+fn cooldown_remaining(last_request: Instant, now: Instant, cooldown: Duration) -> Option<Duration> {
+    cooldown.checked_sub(now.saturating_duration_since(last_request)).filter(|remaining| !remaining.is_zero())
+}
+
+/// Renders durations like `750ms`, `1.5s`, `2m 3s`, or `1h 0m 5s`.
+fn format_duration(duration: chrono::Duration) -> String {
+    if duration < chrono::Duration::seconds(1) {
+        return format!("{}ms", duration.num_milliseconds());
+    }
+    if duration < chrono::Duration::minutes(1) {
+        return format!("{:.1}s", duration.num_milliseconds() as f64 / 1000.0);
+    }
+    let hours = duration.num_hours();
+    let minutes = duration.num_minutes() % 60;
+    let seconds = duration.num_seconds() % 60;
+    if hours == 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{hours}h {minutes}m {seconds}s")
+    }
+}
+
+impl Handler {
+    fn new(cfg: Arc<Config>, metrics: Option<Arc<Mutex<Metrics>>>, recent_requests: Option<Arc<RecentRequests>>) -> Self {
+        let chat_histories = Arc::new(Mutex::new(HashMap::new()));
+        if !cfg.history_ttl.is_zero() {
+            tokio::spawn(prune_idle_histories(Arc::clone(&chat_histories), cfg.history_ttl));
+        }
+        Self {
+            request_permits: Semaphore::new(cfg.max_concurrent_requests),
+            api_keys: KeyPool::new(cfg.openai_api_keys.len()),
+            response_cache: (cfg.response_cache_size > 0).then(|| ResponseCache::new(cfg.response_cache_size, cfg.response_cache_ttl)),
+            spend: (cfg.monthly_budget > 0.0 || cfg.monthly_user_budget > 0.0).then(|| SpendLedger::load(cfg.spend_file.as_str())),
+            guild_configs: GuildConfigs::load(cfg.guild_config_file.as_str()),
+            cfg,
+            chat_histories,
+            usage: Mutex::new(HashMap::new()),
+            last_requests: Mutex::new(HashMap::new()),
+            cancellations: Mutex::new(HashMap::new()),
+            model_list: Mutex::new(None),
+            circuit: Mutex::new(CircuitBreaker::new()),
+            tools: default_tools(),
+            pin_embeddings: Mutex::new(HashMap::new()),
+            continuations: Mutex::new(HashMap::new()),
+            failed_prompts: Mutex::new(HashMap::new()),
+            warned_missing_content: AtomicBool::new(false),
+            commands_registered: AtomicBool::new(false),
+            request_seq: AtomicU64::new(0),
+            halted: AtomicBool::new(false),
+            metrics,
+            recent_requests,
+            started_at: Instant::now(),
+        }
+    }
+
+    fn show_time<TZ: chrono::TimeZone>(&self, ui: &str, source: &str, data: impl std::fmt::Display, start: chrono::DateTime<TZ>, end: chrono::DateTime<TZ>) {
+        let diff = end - start;
+        let diff_ns = diff.num_nanoseconds().unwrap_or(-1);
+        let diff_human = format_duration(diff);
+        // Text logs render the fields as `key=value` after the message.
+        tracing::info!(ui = %ui, source = %source, data = %data, duration_ns = diff_ns, duration_human = %diff_human, "TIMING");
+        self.with_metrics(|metrics| metrics.observe(ui, diff.to_std().unwrap_or_default()));
+    }
+
+    /// A span for everything logged while handling one event, numbered so that lines from events
+    /// handled at the same time can be told apart.
+    fn request_span(&self) -> tracing::Span {
+        let seq = self.request_seq.fetch_add(1, Ordering::Relaxed);
+        tracing::info_span!("request", seq)
+    }
+
+    /// Runs `f` on the metrics, if they're being collected.
+    fn with_metrics(&self, f: impl FnOnce(&mut Metrics)) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            f(&mut metrics.lock());
+        }
+    }
+
+    /// Sends the prompt (and relevant history) to the model. When `stream` is provided, the reply is
+    /// streamed and the partial text is published to it as it comes in. Only the first reply is
+    /// returned, so the request should ask for one.
+    async fn run_chat(&self, request: &ChatRequest<'_>, stream: Option<StreamTarget>) -> Result<String, BotError> {
+        let mut replies = self.run_chat_choices(request, stream).await?;
+        Ok(replies.choices.swap_remove(0))
+    }
+
+    /// Like [`Self::run_chat`], but returns every reply. If there's more than one, they're left
+    /// pending for [`Self::pick_choice`] instead of being added to the history.
+    async fn run_chat_choices(&self, request: &ChatRequest<'_>, stream: Option<StreamTarget>) -> Result<ChatReplies, BotError> {
+        let start = Instant::now();
+        let result = self.chat_choices(request, stream).await;
+        if let Some(recent_requests) = self.recent_requests.as_ref() {
+            recent_requests.push(RequestRecord {
+                at: chrono::Utc::now(),
+                user_name: request.user_name.to_owned(),
+                model: result.as_ref().map_or_else(|_| request.model.clone(), |replies| replies.model.clone()),
+                latency: start.elapsed(),
+                error: result.as_ref().err().map(|e| format!("{e:?}")),
+            });
+        }
+        result
+    }
+
+    async fn chat_choices(&self, request: &ChatRequest<'_>, stream: Option<StreamTarget>) -> Result<ChatReplies, BotError> {
+        let &ChatRequest { key, user_name, ref model, prompt, ref images, ref options, ref reply_chain, .. } = request;
+        log::info!("COMMAND-PARSED model={model:?}, prompt={prompt:?}, images={images:?}, options={options:?}");
+        // Cached and dry run replies never reach OpenAI, so they're refused here too.
+        self.check_halted()?;
+
+        let history = self.active_history(key);
+
+        let client = self.openai_client()?;
+
+        if self.cfg.moderate_prompts && !self.cfg.dry_run {
+            self.moderate(&client, prompt).await?;
+        }
+
+        // Only cleared once the request has gone through, so that a failure doesn't lose them.
+        let pins = history.lock().pins.clone();
+        let pinned_context = if pins.is_empty() {
+            None
+        } else if self.cfg.dry_run {
+            // Without a prompt embedding to rank against, the first pins are as good as any.
+            Some(pinned_context(pins.as_slice(), &[]))
+        } else {
+            let prompt_embedding = self.embed(&client, &[prompt]).await?.swap_remove(0);
+            Some(pinned_context(pins.as_slice(), prompt_embedding.as_slice()))
+        };
+
+        let locked_history = history.lock().clone();
+        let guild_config = self.guild_config(key.guild_id);
+        // Only what's sent is wrapped. The history keeps the prompt as written.
+        let sent_prompt = apply_prompt_template(guild_config.prompt_template.as_deref(), prompt);
+        // Only kept for later turns once the request has gone through.
+        let system_prompt = options.system_prompt.as_deref()
+            .or(locked_history.system_prompt.as_deref())
+            .or(guild_config.system_prompt.as_deref())
+            .or(self.cfg.default_system_prompt.as_deref());
+        let system_prompt: Option<Cow<str>> = match (system_prompt, pinned_context) {
+            (Some(system_prompt), Some(pinned_context)) => Some(format!("{system_prompt}\n\n{pinned_context}").into()),
+            (Some(system_prompt), None) => Some(system_prompt.into()),
+            (None, pinned_context) => pinned_context.map(Cow::from),
+        };
+        let system_prompt = match options.reply_language.as_deref().map(language_directive) {
+            Some(directive) => Some(match system_prompt {
+                Some(system_prompt) => format!("{directive}\n\n{system_prompt}").into(),
+                None => directive.into(),
+            }),
+            None => system_prompt,
+        };
+        let system_prompt = system_prompt.as_deref();
+        if options.json {
+            validate_json_mention(system_prompt, sent_prompt.as_ref())?;
+        }
+        let prompt_line = format!("\n\nPrompt from {user_name}: {sent_prompt}");
+        let history_budget = self.cfg.history_token_budget
+            .saturating_sub(count_tokens(prompt_line.as_str()))
+            .saturating_sub(system_prompt.map(count_tokens).unwrap_or(0));
+        let relevant_history = trim_history(reply_chain.as_deref().unwrap_or(locked_history.turns.as_slice()), history_budget);
+        let prepared = PreparedChat { system_prompt, sent_prompt: sent_prompt.as_ref(), prompt_line: prompt_line.as_str(), history: relevant_history };
+
+        let mut model = model.as_str();
+        let mut completion = self.complete_chat(request, &client, &prepared, model, stream.as_ref()).await;
+        let fallback = self.cfg.fallback_models.get(model).filter(|_| request.fallback);
+        if let (Err(BotError::Overloaded(_)), Some(fallback)) = (&completion, fallback) {
+            // Nothing has been streamed yet, since the request never got through.
+            log::warn!("MODEL-FALLBACK model={model} fallback={fallback} user={:?}", key.user_id);
+            model = fallback.as_str();
+            completion = self.complete_chat(request, &client, &prepared, model, stream.as_ref()).await;
+        }
+        let (choices, logprobs) = completion?;
+
+        let mut history = history.lock();
+        // Pins go along with the one prompt after they're loaded.
+        history.pins.clear();
+        if let Some(system_prompt) = options.system_prompt.as_ref() {
+            history.system_prompt = Some(system_prompt.clone());
+        }
+        history.last_request = Some(LastRequest {
+            user_name: user_name.to_owned(),
+            model: model.to_owned(),
+            prompt: prompt.to_owned(),
+            images: images.clone(),
+            options: options.clone(),
+            turn_start: history.turns.len(),
+        });
+        history.pending_choices = None;
+
+        // Discord refuses to send empty messages, and an empty turn isn't worth remembering.
+        let choices: Vec<_> = choices.into_iter()
+            .map(|choice| if choice.trim().is_empty() { EMPTY_RESPONSE_PLACEHOLDER.to_owned() } else { choice })
+            .collect();
+
+        if choices.len() > 1 {
+            history.pending_choices = Some(PendingChoices {
+                user_name: user_name.to_owned(),
+                model: model.to_owned(),
+                prompt: prompt.to_owned(),
+                choices: choices.clone(),
+            });
+            return Ok(ChatReplies { choices, logprobs, model: model.to_owned() });
+        }
+
+        if choices[0] == EMPTY_RESPONSE_PLACEHOLDER {
+            log::warn!("Model returned an empty response. Not saving it to history.");
+            if let Some(stream) = stream {
+                stream.progress.send_replace(EMPTY_RESPONSE_PLACEHOLDER.to_owned());
+            }
+            return Ok(ChatReplies { choices, logprobs, model: model.to_owned() });
+        }
+
+        history.push_exchange(user_name, prompt, model, choices[0].as_str(), self.cfg.max_history_turns);
+
+        Ok(ChatReplies { choices, logprobs, model: model.to_owned() })
+    }
+
+    /// Sends `prepared` to `model`, which may be the fallback for the one `request` asks for, and
+    /// returns the replies along with the first one's logprobs.
+    async fn complete_chat(&self, request: &ChatRequest<'_>, client: &reqwest::Client, prepared: &PreparedChat<'_>, model: &str, stream: Option<&StreamTarget>) -> Result<(Vec<String>, Option<Vec<TokenLogprobs>>), BotError> {
+        let &ChatRequest { key, ref images, ref options, ref retry_notices, .. } = request;
+        let &PreparedChat { system_prompt, sent_prompt, prompt_line, history: relevant_history } = prepared;
+        let legacy = is_legacy_completion_model(model);
+        let (url, mut request_body) = if legacy {
+            let system_prompt = system_prompt.unwrap_or("");
+            let relevant_history = render_transcript(relevant_history);
+            let relevant_history_with_prompt = format!("{system_prompt}{relevant_history}{prompt_line}");
+            (self.openai_url("/v1/completions"), build_completion(relevant_history_with_prompt.as_str(), options))
+        } else {
+            (self.openai_url("/v1/chat/completions"), build_chat_completion(model, system_prompt, relevant_history, sent_prompt, images.as_slice(), options))
+        };
+
+        request_body["user"] = self.openai_user(key.user_id).into();
+        if stream.is_some() {
+            request_body["stream"] = true.into();
+            // Otherwise streamed requests wouldn't count towards usage.
+            request_body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+        let offer_tools = model_info(model).is_some_and(|info| info.tools) && stream.is_none() && options.n == 1 && self.cfg.max_tool_rounds > 0 && !self.tools.is_empty();
+        if offer_tools {
+            request_body["tools"] = tool_definitions(&self.tools);
+        }
+
+        // Logprobs describe one particular reply, so there's no reusing it for them.
+        let cache_key = self.response_cache.as_ref()
+            .filter(|_| options.logprobs.is_none() && !self.cfg.dry_run)
+            .map(|_| response_cache_key(model, system_prompt, relevant_history, sent_prompt, images.as_slice(), options));
+        let cached = cache_key.as_deref().and_then(|cache_key| self.response_cache.as_ref()?.get(cache_key, Instant::now()));
+        let cache_outcome = match (&cache_key, &cached) {
+            (None, _) => "uncached",
+            (Some(_), Some(_)) => "cache_hit",
+            (Some(_), None) => "cache_miss",
+        };
+        let completion_start = chrono::Utc::now();
+        let (choices, logprobs) = if let Some(choices) = cached {
+            log::info!("Answering from the response cache.");
+            if let Some(stream) = stream.as_ref() {
+                stream.progress.send_replace(choices[0].clone());
+            }
+            (choices, None)
+        } else if self.cfg.dry_run {
+            log::info!("DRY-RUN url={url} body={request_body}");
+            if let Some(stream) = stream.as_ref() {
+                stream.progress.send_replace(DRY_RUN_RESPONSE.to_owned());
+            }
+            (vec![DRY_RUN_RESPONSE.to_owned(); options.n as usize], None)
+        } else {
+            // Only the prompt can be known ahead of time. The reply is paid for after the fact.
+            let prompt_tokens = count_tokens(prompt_line)
+                + relevant_history.iter().map(ChatTurn::count_tokens).sum::<usize>()
+                + system_prompt.map_or(0, count_tokens);
+            self.check_budget(key.user_id, TokenUsage { prompt_tokens: prompt_tokens as u64, ..TokenUsage::default() }.estimated_cost(model))?;
+
+            // Held until the response has been fully read.
+            let _permit = self.acquire_request_permit(model).await?;
+
+            let mut response = self.post_with_retry_notices(client, url.as_str(), &request_body, retry_notices.as_ref()).await?;
+
+            match stream.as_ref() {
+                // Errors come back as a normal JSON body, even when streaming.
+                Some(stream) if response.status().is_success() => {
+                    let (text, usage) = read_completion_stream(response, stream).await?;
+                    log::info!("stream replied with {text:?}");
+                    self.record_usage(key.user_id, model, usage);
+                    (vec![text], None)
+                },
+                _ => {
+                    let mut tool_rounds = 0;
+                    loop {
+                        let outcome: serde_json::Value = match response.json().await {
+                            Ok(value) => value,
+                            Err(e) => {
+                                log::error!("Completion post failed getting body due to {e:?}");
+                                return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+                            },
+                        };
+
+                        log::info!("post replied with {outcome:?}");
+                        let tool_calls = if offer_tools { requested_tool_calls(&outcome) } else { None };
+                        let Some((message, tool_calls)) = tool_calls else {
+                            let logprobs = if options.logprobs.is_some() { first_choice_logprobs(&outcome) } else { None };
+                            let (choices, usage) = parse_completion(outcome)?;
+                            self.record_usage(key.user_id, model, usage);
+                            break (choices, logprobs);
+                        };
+                        self.record_usage(key.user_id, model, serde_json::from_value(outcome["usage"].clone()).ok());
+
+                        tool_rounds += 1;
+                        if tool_rounds > self.cfg.max_tool_rounds {
+                            log::warn!("Model kept calling tools after {} rounds. Giving up.", self.cfg.max_tool_rounds);
+                            return Err(BotError::OpenAi("The model kept calling tools without replying.".into()));
+                        }
+                        let Some(messages) = request_body["messages"].as_array_mut() else {
+                            log::error!("Chat completion request had no messages to add tool results to.");
+                            return Err(BotError::Internal);
+                        };
+                        messages.push(message);
+                        for tool_call in tool_calls {
+                            let result = self.call_tool(&tool_call);
+                            messages.push(serde_json::json!({
+                                "role": "tool",
+                                "tool_call_id": tool_call.id,
+                                "content": result,
+                            }));
+                        }
+                        response = self.post_with_retry_notices(client, url.as_str(), &request_body, retry_notices.as_ref()).await?;
+                    }
+                },
+            }
+        };
+        self.show_time("openai_chat", cache_outcome, model, completion_start, chrono::Utc::now());
+        if options.json && !self.cfg.dry_run {
+            choices.iter().try_for_each(|choice| validate_json_reply(choice))?;
+        }
+        if let (Some(cache), Some(cache_key), "cache_miss") = (self.response_cache.as_ref(), cache_key, cache_outcome) {
+            cache.insert(cache_key, choices.clone(), Instant::now());
+        }
+
+        Ok((choices, logprobs))
+    }
+
+    /// Runs the tool `tool_call` asks for. Anything that goes wrong is described in the result, for
+    /// the model to deal with.
+    fn call_tool(&self, tool_call: &ToolCall) -> String {
+        let ToolCallFunction { name, arguments } = &tool_call.function;
+        let Some(tool) = self.tools.get(name) else {
+            log::warn!("Model called unknown tool `{name}`.");
+            return format!("Error: there's no tool named `{name}`.");
+        };
+        let arguments: serde_json::Value = match serde_json::from_str(arguments) {
+            Ok(arguments) => arguments,
+            Err(e) => {
+                log::warn!("Model called `{name}` with arguments that aren't JSON: {arguments:?}. Error: {e:?}");
+                return format!("Error: the arguments aren't valid JSON: {e}");
+            },
+        };
+        match (tool.call)(&arguments) {
+            Ok(result) => {
+                log::info!("Tool `{name}` called with {arguments} returned {result:?}");
+                result
+            },
+            Err(e) => {
+                log::warn!("Tool `{name}` called with {arguments} failed: {e}");
+                format!("Error: {e}")
+            },
+        }
+    }
+
+    /// Adds a completion's token usage to the user's totals and the metrics.
+    fn record_usage(&self, user_id: UserId, model: &str, usage: Option<TokenUsage>) {
+        match usage {
+            Some(usage) => {
+                self.usage.lock().entry(user_id).or_default().record(model, usage);
+                if let Some(spend) = self.spend.as_ref() {
+                    spend.record(user_id, usage.estimated_cost(model));
+                }
+                self.with_metrics(|metrics| metrics.record_tokens(model, usage));
+            },
+            None => log::warn!("Completion response did not report usage."),
+        }
+    }
+
+    /// Keeps the `index`th pending reply in the history. Returns the prompt and the kept reply.
+    fn pick_choice(&self, key: HistoryKey, index: usize) -> Result<(String, String), BotError> {
+        let history = self.chat_histories.lock().get(&key).cloned();
+        let picked = history.and_then(|history| {
+            let mut history = history.lock();
+            let pending = history.pending_choices.take()?;
+            let Some(choice) = pending.choices.get(index).cloned() else {
+                log::warn!("Picked choice {index} of {}.", pending.choices.len());
+                history.pending_choices = Some(pending);
+                return None;
+            };
+            history.push_exchange(pending.user_name.as_str(), pending.prompt.as_str(), pending.model.as_str(), choice.as_str(), self.cfg.max_history_turns);
+            Some((pending.prompt, choice))
+        });
+        picked.ok_or_else(|| {
+            log::warn!("Nothing to pick for {key:?}.");
+            BotError::UserInput("There's nothing to pick from anymore. Only whoever asked can pick, and only for their latest prompt.".into())
+        })
+    }
+
+    /// Embeds each of `inputs`, in the same order.
+    async fn embed(&self, client: &reqwest::Client, inputs: &[&str]) -> Result<Vec<Vec<f32>>, BotError> {
+        let request_body = serde_json::json!({ "model": EMBEDDING_MODEL, "input": inputs });
+        let response = self.post_with_retries(client, self.openai_url("/v1/embeddings").as_str(), &request_body).await?;
+        let outcome: serde_json::Value = match response.json().await {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("Embedding post failed getting body due to {e:?}");
+                return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+            },
+        };
+
+        if outcome.get("error").is_some() {
+            return Err(parse_error(outcome));
+        }
+        let mut response: EmbeddingResponse = serde_json::from_value(outcome).map_err(|e| {
+            log::error!("Embedding response could not be parsed. Error: {e:?}");
+            BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into())
+        })?;
+        if response.data.len() != inputs.len() {
+            log::error!("Asked for {} embeddings, but got {}.", inputs.len(), response.data.len());
+            return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into()));
+        }
+        response.data.sort_by_key(|data| data.index);
+        Ok(response.data.into_iter().map(|data| data.embedding).collect())
+    }
+
+    /// Embeds the messages pinned in `channel_id`, so that the most relevant ones are sent with the
+    /// next prompt in the conversation. Returns how many were loaded.
+    async fn load_pins(&self, ctx: &Context, channel_id: ChannelId, key: HistoryKey) -> Result<usize, BotError> {
+        let pinned = channel_id.pins(ctx).await.map_err(|e| {
+            log::error!("Failed to fetch pinned messages in {channel_id:?}. Error: {e:?}");
+            BotError::UserInput("Couldn't fetch the pinned messages here. Can I read this channel's history?".into())
+        })?;
+        let pinned: Vec<_> = pinned.into_iter().filter(|message| !message.content.trim().is_empty()).collect();
+        log::info!("COMMAND-PARSED pins channel={channel_id:?} count={}", pinned.len());
+
+        let uncached: Vec<_> = {
+            let pin_embeddings = self.pin_embeddings.lock();
+            pinned.iter().filter(|message| !pin_embeddings.contains_key(&message.id)).collect()
+        };
+        if !uncached.is_empty() {
+            let client = self.openai_client()?;
+            let inputs: Vec<_> = uncached.iter().map(|message| message.content.as_str()).collect();
+            let embeddings = self.embed(&client, inputs.as_slice()).await?;
+            let mut pin_embeddings = self.pin_embeddings.lock();
+            for (message, embedding) in uncached.into_iter().zip(embeddings) {
+                pin_embeddings.insert(message.id, Arc::new(embedding));
+            }
+        }
+
+        let pins: Vec<_> = {
+            let pin_embeddings = self.pin_embeddings.lock();
+            pinned.into_iter().filter_map(|message| Some(PinnedMessage {
+                embedding: Arc::clone(pin_embeddings.get(&message.id)?),
+                content: message.content,
+            })).collect()
+        };
+        let count = pins.len();
+        self.active_history(key).lock().pins = pins;
+
+        Ok(count)
+    }
+
+    /// Runs `prompt` through OpenAI's moderation endpoint, refusing it if it gets flagged.
+    async fn moderate(&self, client: &reqwest::Client, prompt: &str) -> Result<(), BotError> {
+        let request_body = serde_json::json!({ "input": prompt });
+        let response = self.post_with_retries(client, self.openai_url("/v1/moderations").as_str(), &request_body).await?;
+        let outcome: serde_json::Value = match response.json().await {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("Moderation post failed getting body due to {e:?}");
+                return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+            },
+        };
+
+        if outcome.get("error").is_some() {
+            return Err(parse_error(outcome));
+        }
+        let response: ModerationResponse = serde_json::from_value(outcome).map_err(|e| {
+            log::error!("Moderation response could not be parsed. Error: {e:?}");
+            BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into())
+        })?;
+
+        match response.results.iter().find(|result| result.flagged) {
+            Some(result) => {
+                let categories = result.flagged_categories().join(", ");
+                log::warn!("Prompt was flagged by moderation. Categories: {categories}");
+                Err(BotError::UserInput(format!("Sorry, that prompt was flagged by moderation for: {categories}.")))
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Transcribes an audio attachment with Whisper.
+    async fn transcribe(&self, attachment: &Attachment) -> Result<String, BotError> {
+        log::info!("COMMAND-PARSED transcribe filename={:?}, size={}", attachment.filename, attachment.size);
+
+        if attachment.size > self.cfg.max_audio_bytes {
+            let limit_mb = self.cfg.max_audio_bytes as f64 / (1024.0 * 1024.0);
+            log::warn!("Audio should be at most {} bytes. Found {} bytes.", self.cfg.max_audio_bytes, attachment.size);
+            return Err(BotError::UserInput(format!("That audio file is too big. It should be at most {limit_mb:.1} MB.")));
+        }
+
+        let audio = attachment.download().await.map_err(|e| {
+            log::error!("Failed to download audio attachment. Error: {e:?}");
+            BotError::UserInput("Couldn't download that audio file, try again in a bit.".into())
+        })?;
+
+        let client = self.openai_client()?;
+        let response = self.send_with_retries(|| {
+            let file = reqwest::multipart::Part::bytes(audio.clone()).file_name(attachment.filename.clone());
+            let form = reqwest::multipart::Form::new()
+                .text("model", "whisper-1")
+                .part("file", file);
+            client.post(self.openai_url("/v1/audio/transcriptions")).multipart(form)
+        }, None).await?;
+        let outcome: serde_json::Value = match response.json().await {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("Transcription post failed getting body due to {e:?}");
+                return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+            },
+        };
+
+        log::info!("post replied with {outcome:?}");
+        if outcome.get("error").is_some() {
+            return Err(parse_error(outcome));
+        }
+        let response: TranscriptionResponse = serde_json::from_value(outcome).map_err(|e| {
+            log::error!("Transcription response could not be parsed. Error: {e:?}");
+            BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into())
+        })?;
+
+        Ok(response.text)
+    }
+
+    /// Generates an image from `prompt` with DALL-E, returning the URLs of the results.
+    async fn generate_image(&self, prompt: &str, size: &str) -> Result<Vec<String>, BotError> {
+        log::info!("COMMAND-PARSED image prompt={prompt:?}, size={size:?}");
+
+        if !IMAGE_SIZES.contains(&size) {
+            let sizes = IMAGE_SIZES.join(", ");
+            log::warn!("Image size should be one of: {sizes}. Found `{size}`.");
+            return Err(BotError::UserInput(format!("Image size should be one of: {sizes}. Found `{size}`.")));
+        }
+
+        let client = self.openai_client()?;
+
+        let request_body = build_image_generation(prompt, size);
+        let response = self.post_with_retries(&client, self.openai_url("/v1/images/generations").as_str(), &request_body).await?;
+        let outcome: serde_json::Value = match response.json().await {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("Image generation post failed getting body due to {e:?}");
+                return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+            },
+        };
+
+        log::info!("post replied with {outcome:?}");
+        if outcome.get("error").is_some() {
+            return Err(parse_error(outcome));
+        }
+        let response: ImageResponse = serde_json::from_value(outcome).map_err(|e| {
+            log::error!("Image generation response could not be parsed. Error: {e:?}");
+            BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into())
+        })?;
+
+        Ok(response.data.into_iter().map(|image| image.url).collect())
+    }
+
+    /// Checks whether OpenAI can be reached, describing the outcome for the user.
+    async fn ping_openai(&self) -> String {
+        let client = match self.openai_client() {
+            Ok(client) => client,
+            Err(e) => return format!("Couldn't build a client: {e}"),
+        };
+        let start = Instant::now();
+        let key = self.api_keys.pick(Instant::now());
+        match self.authorize(client.get(self.openai_url("/v1/models")), key).timeout(PING_TIMEOUT).send().await {
+            Ok(response) if response.status().is_success() => format!("Reachable ({}ms)", start.elapsed().as_millis()),
+            Ok(response) => format!("Responded with {} ({}ms)", response.status(), start.elapsed().as_millis()),
+            Err(e) if e.is_timeout() => {
+                log::warn!("OpenAI health check timed out. Error: {e:?}");
+                format!("Timed out after {PING_TIMEOUT:?}")
+            },
+            Err(e) => {
+                log::warn!("OpenAI health check failed. Error: {e:?}");
+                "Unreachable".to_owned()
+            },
+        }
+    }
+
+    /// Chat models available from OpenAI, sorted. Reuses the last fetch until it's older than
+    /// `model_list_ttl`, and falls back to [`MODELS`] if OpenAI can't be asked.
+    async fn available_models(&self) -> Vec<String> {
+        if let Some((fetched_at, models)) = self.model_list.lock().as_ref() {
+            if fetched_at.elapsed() < self.cfg.model_list_ttl {
+                return models.clone();
+            }
+        }
+
+        match self.fetch_models().await {
+            Ok(models) => {
+                *self.model_list.lock() = Some((Instant::now(), models.clone()));
+                models
+            },
+            Err(e) => {
+                log::warn!("Failed to fetch the model list. Falling back to the built in one. Error: {e:?}");
+                MODELS.iter().map(|info| info.id.to_owned()).collect()
+            },
+        }
+    }
+
+    async fn fetch_models(&self) -> Result<Vec<String>, BotError> {
+        let client = self.openai_client()?;
+        let key = self.api_keys.pick(Instant::now());
+        let response = match self.authorize(client.get(self.openai_url("/v1/models")), key).timeout(self.cfg.request_timeout).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Model list get failed due to {e:?}");
+                return Err(BotError::OpenAi("Couldn't reach OpenAI, try again in a bit.".into()));
+            },
+        };
+        let outcome: serde_json::Value = match response.json().await {
+            Ok(value) => value,
+            Err(e) => {
+                log::error!("Model list get failed getting body due to {e:?}");
+                return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+            },
+        };
+
+        if outcome.get("error").is_some() {
+            return Err(parse_error(outcome));
+        }
+        let response: ModelListResponse = serde_json::from_value(outcome).map_err(|e| {
+            log::error!("Model list response could not be parsed. Error: {e:?}");
+            BotError::OpenAi("OpenAI sent back a response that couldn't be understood.".into())
+        })?;
+
+        let mut models: Vec<_> = response.data.into_iter()
+            .map(|model| model.id)
+            .filter(|model| is_chat_model(model))
+            .collect();
+        models.sort_unstable();
+        Ok(models)
+    }
+
+    /// Pops the most recent turn off of the history and re-runs its request. Returns the prompt
+    /// along with the new response.
+    async fn regenerate(&self, key: HistoryKey) -> Result<(String, String), BotError> {
+        let history = self.chat_histories.lock().get(&key).cloned();
+        let popped = history.and_then(|history| {
+            let mut history = history.lock();
+            let last_request = history.last_request.take()?;
+            let popped_turns = history.turns.split_off(last_request.turn_start);
+            Some((last_request, popped_turns))
+        });
+        let Some((last_request, popped_turns)) = popped else {
+            log::warn!("Nothing to regenerate for {key:?}.");
+            return Err(BotError::UserInput("There's nothing to regenerate yet. Send a prompt first.".into()));
+        };
+
+        let LastRequest { user_name, model, prompt, images, options, turn_start } = last_request.clone();
+        let request = ChatRequest {
+            key,
+            user_name: user_name.as_str(),
+            model,
+            prompt: prompt.as_str(),
+            images,
+            // Regenerating replaces a reply that's already in the history, so there's nothing to pick from.
+            options: CompletionOptions { n: 1, ..options },
+            reply_chain: None,
+            retry_notices: None,
+            fallback: false,
+        };
+        match self.run_chat(&request, None).await {
+            Ok(response) => Ok((prompt, response)),
+            Err(e) => {
+                // Put things back the way they were, so that a failure doesn't lose the turn.
+                if let Some(history) = self.chat_histories.lock().get(&key) {
+                    let mut history = history.lock();
+                    if history.turns.len() == turn_start {
+                        history.turns.extend(popped_turns);
+                        history.last_request = Some(last_request);
+                    }
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Fills in what `requested` leaves out with the conversation's model, then `key`'s guild's
+    /// defaults, then the bot's, and validates the result. Returns the model along with the options.
+    fn resolve_options(&self, key: HistoryKey, requested: RequestedOptions) -> Result<(String, CompletionOptions), BotError> {
+        let guild_config = self.guild_config(key.guild_id);
+        let conversation_model = self.chat_histories.lock().get(&key).and_then(|history| history.lock().model.clone());
+        let model = requested.model
+            .or(conversation_model.as_deref())
+            .or(guild_config.model.as_deref())
+            .unwrap_or(self.cfg.default_model.as_str())
+            .to_owned();
+        validate_model(model.as_str())?;
+        validate_sampling(model.as_str(), &requested)?;
+        let temperature = match requested.temperature {
+            Some(temperature) => validate_temperature(temperature)?,
+            None => guild_config.temperature.unwrap_or(self.cfg.default_temperature),
+        };
+        let max_tokens = requested.max_tokens
+            .map(|max_tokens| validate_max_tokens(model.as_str(), max_tokens))
+            .transpose()?
+            .unwrap_or(DEFAULT_MAX_TOKENS);
+        let stop = requested.stop
+            .map(|stop| validate_stop(parse_stop(stop)))
+            .transpose()?
+            .unwrap_or_else(|| self.cfg.default_stop.clone());
+        let top_p = requested.top_p.map(validate_top_p).transpose()?;
+        let presence_penalty = requested.presence_penalty
+            .map(|penalty| validate_penalty("Presence penalty", penalty))
+            .transpose()?;
+        let frequency_penalty = requested.frequency_penalty
+            .map(|penalty| validate_penalty("Frequency penalty", penalty))
+            .transpose()?;
+        let n = requested.choices
+            .map(validate_choices)
+            .transpose()?
+            .unwrap_or(1);
+        let logprobs = requested.logprobs
+            .map(|logprobs| validate_logprobs(model.as_str(), logprobs))
+            .transpose()?;
+        let reply_language = match requested.language {
+            Some(language) => Some(validate_language(language)?.to_owned()),
+            None => self.cfg.default_reply_language.clone(),
+        };
+        let json = requested.json.unwrap_or(false);
+        if json {
+            validate_json_mode(model.as_str())?;
+        }
+        let options = CompletionOptions {
+            temperature,
+            max_tokens,
+            system_prompt: requested.system_prompt.map(str::to_owned),
+            stop,
+            top_p,
+            presence_penalty,
+            frequency_penalty,
+            n,
+            seed: requested.seed,
+            logprobs,
+            reply_language,
+            json,
+        };
+        Ok((model, options))
+    }
+
+    /// How `user_id` is identified to OpenAI, which uses it to monitor abuse. The same user always
+    /// gets the same identifier.
+    fn openai_user(&self, user_id: UserId) -> String {
+        if !self.cfg.hash_user_ids {
+            return user_id.0.to_string();
+        }
+        let hash = Sha256::digest(user_id.0.to_string().as_bytes());
+        hash.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// `path` on the OpenAI API, like `/v1/models`.
+    fn openai_url(&self, path: &str) -> String {
+        let path = if self.cfg.openai_azure { path.strip_prefix("/v1").unwrap_or(path) } else { path };
+        match self.cfg.openai_api_version.as_deref() {
+            Some(api_version) => format!("{}{path}?api-version={api_version}", self.cfg.openai_base_url),
+            None => format!("{}{path}", self.cfg.openai_base_url),
+        }
+    }
+
+    fn openai_client(&self) -> Result<reqwest::Client, BotError> {
+        build_openai_client(self.cfg.openai_org_id.as_deref(), self.cfg.connect_timeout).map_err(|e| {
+            log::warn!("OpenAI client build failed. Error: {e:?}");
+            BotError::Internal
+        })
+    }
+
+    /// Adds the API key at `key` in the pool to `request`.
+    fn authorize(&self, request: reqwest::RequestBuilder, key: usize) -> reqwest::RequestBuilder {
+        let api_key = self.cfg.openai_api_keys[key].as_str();
+        if self.cfg.openai_azure {
+            request.header("api-key", api_key)
+        } else {
+            request.bearer_auth(api_key)
+        }
+    }
+
+    /// Posts `body` to `url`, retrying with exponential backoff whenever OpenAI is rate limiting us or
+    /// having trouble of its own.
+    async fn post_with_retries(&self, client: &reqwest::Client, url: &str, body: &serde_json::Value) -> Result<reqwest::Response, BotError> {
+        self.post_with_retry_notices(client, url, body, None).await
+    }
+
+    /// Like [`Self::post_with_retries`], but tells `retries` the number of the next attempt before
+    /// each retry.
+    async fn post_with_retry_notices(&self, client: &reqwest::Client, url: &str, body: &serde_json::Value, retries: Option<&watch::Sender<u32>>) -> Result<reqwest::Response, BotError> {
+        self.send_with_retries(|| client.post(url).json(body), retries).await
+    }
+
+    /// Sends the request made by `request`, retrying like [`Self::post_with_retries`]. A fresh request
+    /// is made for every attempt, since not every body can be reused. Refused outright while the
+    /// circuit breaker is open.
+    async fn send_with_retries(&self, request: impl Fn() -> reqwest::RequestBuilder, retries: Option<&watch::Sender<u32>>) -> Result<reqwest::Response, BotError> {
+        self.check_halted()?;
+        if !self.circuit.lock().allow(Instant::now(), self.cfg.circuit_cooldown) {
+            log::warn!("Refusing OpenAI request while the circuit breaker is open.");
+            return Err(BotError::OpenAi("OpenAI is temporarily unavailable, try again in a bit.".into()));
+        }
+        let result = self.send_with_backoff(request, retries).await;
+        let succeeded = result.as_ref().is_ok_and(|response| !is_outage(response.status()));
+        self.circuit.lock().record(succeeded, Instant::now(), &self.cfg);
+        result
+    }
+
+    async fn send_with_backoff(&self, request: impl Fn() -> reqwest::RequestBuilder, retries: Option<&watch::Sender<u32>>) -> Result<reqwest::Response, BotError> {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let key = self.api_keys.pick(Instant::now());
+            let response = match self.authorize(request(), key).timeout(self.cfg.request_timeout).send().await {
+                Ok(response) => response,
+                Err(e) if e.is_timeout() => {
+                    log::error!("Completion post timed out after {:?}. Error: {e:?}", self.cfg.request_timeout);
+                    return Err(BotError::OpenAi("The AI took too long to respond, try again in a bit.".into()));
+                },
+                Err(e) => {
+                    log::error!("Completion post failed due to {e:?}");
+                    return Err(BotError::OpenAi("Couldn't reach OpenAI, try again in a bit.".into()));
+                },
+            };
+
+            let status = response.status();
+            if status == reqwest::StatusCode::UNAUTHORIZED {
+                self.api_keys.bench(key, REJECTED_KEY_BENCH);
+                // Only worth retrying if there's another key to try.
+                if self.api_keys.has_available(Instant::now()) && attempt < self.cfg.retry_max_attempts {
+                    log::warn!("RETRY attempt={attempt} status={status} key={key} delay=0s");
+                    attempt += 1;
+                    if let Some(retries) = retries {
+                        retries.send_replace(attempt);
+                    }
+                    continue;
+                }
+            }
+            if !is_retryable(status) {
+                return Ok(response);
+            }
+
+            let mut delay = retry_after(&response).unwrap_or_else(|| backoff_delay(attempt));
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                self.api_keys.bench(key, delay);
+                // Another key isn't held back by this one's limit.
+                if self.api_keys.has_available(Instant::now()) {
+                    delay = Duration::ZERO;
+                }
+            }
+            if attempt >= self.cfg.retry_max_attempts || start.elapsed() + delay > self.cfg.retry_max_elapsed {
+                log::error!("RETRY-EXHAUSTED attempt={attempt} status={status} elapsed={:?}", start.elapsed());
+                let message = "The AI is busy right now, try again in a bit.".to_owned();
+                return Err(if is_overloaded(status) { BotError::Overloaded(message) } else { BotError::OpenAi(message) });
+            }
+
+            log::warn!("RETRY attempt={attempt} status={status} key={key} delay={delay:?}");
+            if let Some(retries) = retries {
+                retries.send_replace(attempt + 1);
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Refuses to answer while an admin has stopped generation.
+    fn check_halted(&self) -> Result<(), BotError> {
+        if !self.halted.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        log::warn!("Refusing to answer while generation is stopped.");
+        Err(BotError::UserInput("An admin has stopped the bot from answering for now. Try again later.".into()))
+    }
+
+    /// Stops or resumes sending requests to OpenAI, on behalf of `user_id`, who has to be an admin.
+    /// Stopping also stops every reply that's streaming in. Returns the confirmation to show.
+    fn set_halted(&self, user_id: UserId, halted: bool) -> Result<String, BotError> {
+        if !self.cfg.admin_users.contains(&user_id) {
+            log::warn!("User {user_id:?} tried to {} generation, but isn't an admin.", if halted { "stop" } else { "resume" });
+            return Err(BotError::UserInput("Only the bot's admins can stop or resume generation.".into()));
+        }
+        self.halted.store(halted, Ordering::SeqCst);
+        if !halted {
+            log::warn!("RESUME user={user_id:?}");
+            return Ok("Resumed generation. New requests will be answered again.".to_owned());
+        }
+        let cancelled = {
+            let cancellations = self.cancellations.lock();
+            for (_, cancel) in cancellations.values() {
+                cancel.cancel();
+            }
+            cancellations.len()
+        };
+        log::warn!("HALT user={user_id:?} cancelled_streams={cancelled}");
+        Ok(format!("Stopped generation. New requests are refused, and {cancelled} streaming replies were stopped. Use `resume` to start again."))
+    }
+
+    /// Records a request to OpenAI from `user_id`, unless they're still cooling down from their last
+    /// one.
+    fn check_cooldown(&self, user_id: UserId) -> Result<(), BotError> {
+        if self.cfg.cooldown.is_zero() || self.cfg.admin_users.contains(&user_id) {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut last_requests = self.last_requests.lock();
+        let remaining = last_requests.get(&user_id).and_then(|last_request| cooldown_remaining(*last_request, now, self.cfg.cooldown));
+        if let Some(remaining) = remaining {
+            let secs = remaining.as_secs_f64().ceil();
+            log::warn!("User {user_id:?} is on cooldown for another {remaining:?}.");
+            return Err(BotError::UserInput(format!("Slow down! You can send another request in {secs} seconds.")));
+        }
+        last_requests.insert(user_id, now);
+        Ok(())
+    }
+
+    /// Refuses a request from `user_id` expected to cost `estimated_cost`, in USD, if it'd take this
+    /// month's spend past a budget.
+    fn check_budget(&self, user_id: UserId, estimated_cost: f64) -> Result<(), BotError> {
+        let Some(spend) = self.spend.as_ref() else {
+            return Ok(());
+        };
+        let (total, by_user) = spend.spent(user_id);
+        let budget = self.cfg.monthly_budget;
+        if budget > 0.0 && total + estimated_cost > budget {
+            let remaining = (budget - total).max(0.0);
+            log::warn!("BUDGET-EXCEEDED scope=global spent={total} budget={budget} estimated_cost={estimated_cost}");
+            return Err(BotError::UserInput(format!("The bot's budget for this month is almost used up, with ${remaining:.4} of ${budget:.2} left, and this request would cost about ${estimated_cost:.4}. Try a shorter prompt, or wait for next month.")));
+        }
+        let budget = self.cfg.monthly_user_budget;
+        if budget > 0.0 && !self.cfg.admin_users.contains(&user_id) && by_user + estimated_cost > budget {
+            let remaining = (budget - by_user).max(0.0);
+            log::warn!("BUDGET-EXCEEDED scope=user user={user_id:?} spent={by_user} budget={budget} estimated_cost={estimated_cost}");
+            return Err(BotError::UserInput(format!("Your budget for this month is almost used up, with ${remaining:.4} of ${budget:.2} left, and this request would cost about ${estimated_cost:.4}. Try a shorter prompt, or wait for next month.")));
+        }
+        Ok(())
+    }
+
+    /// The chat history for `key`, made if there isn't one yet, and marked as just used.
+    fn active_history(&self, key: HistoryKey) -> Arc<Mutex<ChatHistory>> {
+        let history = Arc::clone(self.chat_histories.lock().entry(key).or_default());
+        history.lock().last_active = Some(Instant::now());
+        history
+    }
+
+    /// Makes `model` the one `key`'s conversation uses whenever a request doesn't name one.
+    fn set_model(&self, key: HistoryKey, model: &str) -> Result<(), BotError> {
+        validate_model(model)?;
+        self.active_history(key).lock().model = Some(model.to_owned());
+        log::info!("Conversation {key:?} now uses model `{model}`.");
+        Ok(())
+    }
+
+    /// Replaces the system prompt of `key`'s conversation with `persona`, keeping its turns. `reset`
+    /// puts the default back instead. Returns the confirmation to show.
+    fn set_persona(&self, key: HistoryKey, persona: &str) -> Result<String, BotError> {
+        let persona = persona.trim();
+        if persona.eq_ignore_ascii_case("reset") {
+            self.active_history(key).lock().system_prompt = None;
+            log::info!("Conversation {key:?} now uses the default persona.");
+            let guild_config = self.guild_config(key.guild_id);
+            let default = guild_config.system_prompt.as_deref().or(self.cfg.default_system_prompt.as_deref());
+            return Ok(persona_reply(default, true));
+        }
+        validate_prompt_length(persona, self.cfg.max_prompt_chars)?;
+        self.active_history(key).lock().system_prompt = Some(persona.to_owned());
+        log::info!("Conversation {key:?} has a new persona: {persona:?}");
+        Ok(persona_reply(Some(persona), false))
+    }
+
+    fn guild_config(&self, guild_id: Option<GuildId>) -> GuildConfig {
+        guild_id.and_then(|guild_id| self.guild_configs.get(guild_id)).unwrap_or_default()
+    }
+
+    /// The `requested` reply format, or else `guild_id`'s default.
+    fn reply_format(&self, guild_id: Option<GuildId>, requested: Option<&str>) -> Result<ReplyFormat, BotError> {
+        match requested {
+            Some(format) => validate_reply_format(format),
+            None => Ok(self.guild_config(guild_id).reply_format.unwrap_or_default()),
+        }
+    }
+
+    /// Applies the options of a `config` command to the invoking guild's configuration. Returns the
+    /// resulting configuration, described for the user.
+    fn configure_guild(&self, appcommand: &ApplicationCommandInteraction) -> Result<String, BotError> {
+        let Some(guild_id) = appcommand.guild_id else {
+            log::warn!("Config command used outside of a guild.");
+            return Err(BotError::UserInput("Configuration only applies to servers.".into()));
+        };
+        if !is_guild_manager(appcommand) {
+            log::warn!("User {:?} is not allowed to configure guild {guild_id:?}.", appcommand.user.id);
+            return Err(BotError::UserInput("Only server managers can change the configuration.".into()));
+        }
+
+        let option_value = |name: &str| appcommand.data.options.iter().find(|o| o.name == name).and_then(|o| o.value.as_ref());
+        let model = option_value("model").and_then(|v| v.as_str());
+        if let Some(model) = model {
+            validate_model(model)?;
+        }
+        let temperature = option_value("temperature").and_then(|v| v.as_f64()).map(validate_temperature).transpose()?;
+        let system_prompt = option_value("system").and_then(|v| v.as_str());
+        let prompt_template = option_value("template").and_then(|v| v.as_str()).map(validate_prompt_template).transpose()?;
+        let reply_format = option_value("format").and_then(|v| v.as_str()).map(validate_reply_format).transpose()?;
+        let reset = option_value("reset").and_then(|v| v.as_bool()).unwrap_or(false);
+
+        let described = self.guild_configs.update(guild_id, |guild_config| {
+            if reset {
+                *guild_config = GuildConfig::default();
+            }
+            if let Some(model) = model {
+                guild_config.model = Some(model.to_owned());
+            }
+            if let Some(temperature) = temperature {
+                guild_config.temperature = Some(temperature);
+            }
+            if let Some(system_prompt) = system_prompt {
+                guild_config.system_prompt = Some(system_prompt.to_owned());
+            }
+            if let Some(prompt_template) = prompt_template {
+                guild_config.prompt_template = Some(prompt_template.to_owned());
+            }
+            if let Some(reply_format) = reply_format {
+                guild_config.reply_format = Some(reply_format);
+            }
+            log::info!("Guild {guild_id:?} is now configured with {guild_config:?}.");
+
+            guild_config.describe()
+        });
+
+        Ok(described)
+    }
+
+    /// Removes the most recent exchange from the history, or brings back what the most recent
+    /// summary replaced. Returns what was undone, if anything.
+    async fn undo(&self, key: HistoryKey) -> Result<Option<Undone>, BotError> {
+        let Some(history) = self.chat_histories.lock().get(&key).cloned() else {
+            return Ok(None);
+        };
+        let mut history = history.lock();
+        if let Some(turns) = history.before_summary.take() {
+            history.turns = turns;
+            return Ok(Some(Undone::Summary));
+        }
+        let Some(exchange_start) = history.turns.iter().rposition(|turn| turn.role == Role::User) else {
+            return Ok(None);
+        };
+        history.turns.truncate(exchange_start);
+        // What's left to regenerate is no longer the most recent request.
+        history.last_request = None;
+
+        Ok(Some(Undone::Exchange))
+    }
+
+    /// Replaces the history with a summary of it, written by the model. The replaced turns can be
+    /// brought back with [`Self::undo`] until the next exchange. Returns the summary.
+    async fn summarize(&self, key: HistoryKey) -> Result<String, BotError> {
+        self.check_halted()?;
+        let Some(history) = self.chat_histories.lock().get(&key).cloned() else {
+            return Err(BotError::UserInput(NOTHING_TO_SUMMARIZE.into()));
+        };
+        let locked_history = history.lock().clone();
+        if locked_history.turns.is_empty() {
+            return Err(BotError::UserInput(NOTHING_TO_SUMMARIZE.into()));
+        }
+
+        let (model, options) = self.resolve_options(key, RequestedOptions::default())?;
+        let model = model.as_str();
+        log::info!("COMMAND-PARSED summarize model={model:?} turns={}", locked_history.turns.len());
+        let system_prompt = locked_history.system_prompt.as_deref();
+        let (url, mut request_body) = if is_legacy_completion_model(model) {
+            let transcript = render_transcript(locked_history.turns.as_slice());
+            let prompt = format!("{}{transcript}\n\n{SUMMARY_INSTRUCTION}\n", system_prompt.unwrap_or(""));
+            (self.openai_url("/v1/completions"), build_completion(prompt.as_str(), &options))
+        } else {
+            (self.openai_url("/v1/chat/completions"), build_chat_completion(model, system_prompt, locked_history.turns.as_slice(), SUMMARY_INSTRUCTION, &[], &options))
+        };
+        request_body["user"] = self.openai_user(key.user_id).into();
+
+        let summary = if self.cfg.dry_run {
+            log::info!("DRY-RUN url={url} body={request_body}");
+            DRY_RUN_RESPONSE.to_owned()
+        } else {
+            let prompt_tokens = locked_history.turns.iter().map(ChatTurn::count_tokens).sum::<usize>() + system_prompt.map_or(0, count_tokens);
+            self.check_budget(key.user_id, TokenUsage { prompt_tokens: prompt_tokens as u64, ..TokenUsage::default() }.estimated_cost(model))?;
+            let client = self.openai_client()?;
+            let _permit = self.acquire_request_permit(model).await?;
+            let response = self.post_with_retries(&client, url.as_str(), &request_body).await?;
+            let outcome: serde_json::Value = match response.json().await {
+                Ok(value) => value,
+                Err(e) => {
+                    log::error!("Summary post failed getting body due to {e:?}");
+                    return Err(BotError::OpenAi("OpenAI sent back a response that couldn't be read.".into()));
+                },
+            };
+            let (choices, usage) = parse_completion(outcome)?;
+            self.record_usage(key.user_id, model, usage);
+            choices.into_iter().next().unwrap_or_default()
+        };
+        let summary = summary.trim().to_owned();
+        if summary.is_empty() {
+            log::warn!("Model returned an empty summary. Keeping the history as is.");
+            return Err(BotError::OpenAi("OpenAI sent back an empty summary, so nothing was changed.".into()));
+        }
+
+        let mut history = history.lock();
+        let turns = std::mem::replace(&mut history.turns, vec![ChatTurn {
+            role: Role::System,
+            name: "summary".to_owned(),
+            content: format!("Summary of the conversation so far:\n{summary}"),
+            at: chrono::Utc::now(),
+        }]);
+        history.before_summary = Some(turns);
+        // The turns these point at are gone.
+        history.last_request = None;
+        history.pending_choices = None;
+
+        Ok(summary)
+    }
+
+    /// Waits for a free request slot, giving up after the queue timeout. OpenAI requests should hold
+    /// on to it until their response has been fully read.
+    async fn acquire_request_permit(&self, model: &str) -> Result<tokio::sync::SemaphorePermit<'_>, BotError> {
+        let queue_start = chrono::Utc::now();
+        let permit = match tokio::time::timeout(self.cfg.request_queue_timeout, self.request_permits.acquire()).await {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(e)) => {
+                log::error!("Request semaphore was closed. Error: {e:?}");
+                return Err(BotError::Internal);
+            },
+            Err(_) => {
+                log::warn!("Timed out waiting for a request slot after {:?}.", self.cfg.request_queue_timeout);
+                return Err(BotError::OpenAi("Too many requests are in progress right now, try again in a bit.".into()));
+            },
+        };
+        self.show_time("openai_queue", "model", model, queue_start, chrono::Utc::now());
+        Ok(permit)
+    }
+
+    /// The conversation as a Markdown document, or `None` if nothing's been said yet.
+    fn export(&self, key: HistoryKey) -> Option<String> {
+        let history = self.chat_histories.lock().get(&key).cloned()?;
+        let history = history.lock();
+        if history.turns.is_empty() {
+            return None;
+        }
+        Some(render_export(history.system_prompt.as_deref(), history.turns.as_slice()))
+    }
+
+    /// What's remembered of the conversation, or `None` if nothing's been said yet.
+    fn context(&self, key: HistoryKey) -> Option<ContextView> {
+        let history = self.chat_histories.lock().get(&key).cloned()?;
+        let history = history.lock();
+        if history.turns.is_empty() {
+            return None;
+        }
+        Some(render_context(history.system_prompt.as_deref(), history.turns.as_slice()))
+    }
+
+    async fn clear(&self, key: HistoryKey) -> Result<(), BotError> {
+        self.chat_histories.lock().remove(&key);
+
+        Ok(())
+    }
+
+    async fn clear_all(&self, user_id: UserId) -> Result<(), BotError> {
+        self.chat_histories.lock().retain(|key, _| key.user_id != user_id);
+
+        Ok(())
+    }
+
+    /// Clears every chat history in `guild_id`, and puts its configuration back to the defaults in
+    /// [`Config`].
+    async fn reset_guild(&self, guild_id: GuildId) -> Result<(), BotError> {
+        self.chat_histories.lock().retain(|key, _| key.guild_id != Some(guild_id));
+        self.guild_configs.remove(guild_id);
+        log::info!("Guild {guild_id:?} was reset.");
+
+        Ok(())
+    }
+
+    async fn handle_autocomp_and_errors(&self, ctx: Context, autocomplete: AutocompleteInteraction) {
+        log::info!("BEGIN ui=discord_autocomp interaction={autocomplete:?}");
+        let id = autocomplete.id;
+        // Only the model option autocompletes, so if nothing is focused we still suggest models.
+        let partial = autocomplete.data.options.iter()
+            .find(|o| o.focused)
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let res = autocomplete.create_autocomplete_response(&ctx, |response| {
+            for info in matching_models(partial.as_str()) {
+                response.add_string_choice(info.name, info.id);
+            }
+            response
+        }).await;
+        match res {
+            Ok(_) => {
+                log::info!("COMPLETE ui=discord_autocomp interaction={id:?}")
+            },
+            Err(e) => {
+                self.with_metrics(|metrics| metrics.record_error("discord_autocomp"));
+                log::error!("COMPLETE ui=discord_autocomp interaction={id:?} error={e:?} user_error=false")
+            },
+        }
+    }
+
+    async fn handle_msgcomp_and_errors(&self, ctx: Context, msgcomponent: MessageComponentInteraction) {
+        log::info!("BEGIN ui=discord_msgcomp interaction={msgcomponent:?}");
+        let interaction_id = msgcomponent.id;
+        match self.handle_msgcomp(&ctx, &msgcomponent).await {
+            Ok(_) => {
+                log::info!("COMPLETE ui=discord_msgcomp interaction={interaction_id:?} outcome=success");
+            },
+            Err(e0) => {
+                self.with_metrics(|metrics| metrics.record_error("discord_msgcomp"));
+                let user_error = e0.is_user_error();
+                let locale = Locale::from_discord(msgcomponent.locale.as_str());
+                match msgcomponent.create_followup_message(ctx, |m| m.content(e0.user_message(locale)).ephemeral(true)).await {
+                    Ok(_) => {
+                        log::error!("COMPLETE ui=discord_msgcomp interaction={interaction_id:?} outcome=error error={e0:?} user_error={user_error}");
+                    },
+                    Err(e1) => {
+                        log::error!("COMPLETE ui=discord_msgcomp interaction={interaction_id:?} outcome=error primary_error={e0:?} secondary_error={e1:?} user_error={user_error}");
+                    },
+                }
+            },
+        }
+    }
+
+    async fn handle_msgcomp(&self, ctx: &Context, msgcomponent: &MessageComponentInteraction) -> Result<(), BotError> {
+        let action = ComponentAction::parse(msgcomponent.data.custom_id.as_str());
+        let locale = Locale::from_discord(msgcomponent.locale.as_str());
+
+        // Acknowledge before anything can fail, so that errors can always be sent as followups.
+        let (kind, ephemeral) = match action {
+            // The modal has to be the first response, so there's no deferring it.
+            Some(ComponentAction::EditResend) => return self.open_resend_modal(ctx, msgcomponent, locale).await,
+            Some(ComponentAction::Clear) => (InteractionResponseType::DeferredChannelMessageWithSource, true),
+            Some(ComponentAction::Regenerate) => (InteractionResponseType::DeferredChannelMessageWithSource, false),
+            Some(ComponentAction::Stop(_) | ComponentAction::ConfirmClear(_) | ComponentAction::CancelClear(_) | ComponentAction::Pick | ComponentAction::Continue) | None => (InteractionResponseType::DeferredUpdateMessage, false),
+        };
+        let deferral = msgcomponent.create_interaction_response(ctx, |response| {
+            response
+                .kind(kind)
+                .interaction_response_data(|data| data.ephemeral(ephemeral))
+        }).await;
+        if let Err(e) = deferral {
+            log::error!("Message component failed to be deferred. Error: {e:?}");
+            return Err(e.into());
+        }
+
+        let Some(action) = action else {
+            log::warn!("Unknown message component `{}`.", msgcomponent.data.custom_id);
+            return Err(BotError::Internal);
+        };
+
+        // Stopping is checked against who asked instead.
+        let check_allowed = || {
+            let roles = msgcomponent.member.as_ref().map(|member| member.roles.as_slice());
+            if self.cfg.is_allowed(msgcomponent.user.id, roles) {
+                return Ok(());
+            }
+            log::warn!("User {:?} is not allowed to use the bot.", msgcomponent.user.id);
+            Err(BotError::UserInput(Text::NotAuthorized.get(locale).into()))
+        };
+        let resolve_key = || HistoryKey::resolve(ctx, msgcomponent.guild_id, msgcomponent.channel_id, msgcomponent.user.id);
+
+        match action {
+            // Answered with the modal in place of deferring, above.
+            ComponentAction::EditResend => {},
+            ComponentAction::Stop(message_id) => {
+                self.stop_stream(message_id, msgcomponent.user.id)?;
+            },
+            ComponentAction::Clear => {
+                check_allowed()?;
+                msgcomponent.create_followup_message(ctx, |m| {
+                    m
+                        .content(Text::ClearConfirmation.get(locale))
+                        .ephemeral(true)
+                        .components(|components| add_clear_confirmation_buttons(components, msgcomponent.user.id, locale))
+                }).await?;
+            },
+            ComponentAction::ConfirmClear(user_id) => {
+                check_clear_answerer(user_id, msgcomponent.user.id)?;
+                check_allowed()?;
+                let asked_at = msgcomponent.message.timestamp.unix_timestamp();
+                let reply = if chrono::Utc::now().timestamp().saturating_sub(asked_at) > CLEAR_CONFIRMATION_TIMEOUT.as_secs() as i64 {
+                    log::info!("Clear confirmation for {user_id:?} expired.");
+                    Text::ClearExpired.get(locale)
+                } else {
+                    self.clear(resolve_key().await).await?;
+                    Text::HistoryCleared.get(locale)
+                };
+                msgcomponent.edit_original_interaction_response(ctx, |m| m.content(reply).components(|components| components)).await?;
+            },
+            ComponentAction::CancelClear(user_id) => {
+                check_clear_answerer(user_id, msgcomponent.user.id)?;
+                msgcomponent.edit_original_interaction_response(ctx, |m| m.content(Text::HistoryKept.get(locale)).components(|components| components)).await?;
+            },
+            ComponentAction::Regenerate => {
+                check_allowed()?;
+                self.check_cooldown(msgcomponent.user.id)?;
+                let (prompt, response) = self.regenerate(resolve_key().await).await?;
+                let (first, rest) = split_reply(format_reply(prompt.as_str(), response.as_str()).as_str());
+                let message = msgcomponent.create_followup_message(ctx, |m| {
+                    m
+                        .content(first.content)
+                        .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
+                        .components(|components| add_chunk_buttons(components, !rest.is_empty(), locale))
+                }).await?;
+                self.hold_continuation(message.id, rest);
+            },
+            ComponentAction::Pick => {
+                check_allowed()?;
+                let index = msgcomponent.data.values.first().and_then(|value| value.parse().ok()).ok_or_else(|| {
+                    log::error!("Pick had no usable value. Found {:?}.", msgcomponent.data.values);
+                    BotError::Internal
+                })?;
+                let (prompt, response) = self.pick_choice(resolve_key().await, index)?;
+                let (first, rest) = split_reply(format_reply(prompt.as_str(), response.as_str()).as_str());
+                msgcomponent.edit_original_interaction_response(ctx, |m| {
+                    m
+                        .content(first.content)
+                        .set_embeds(vec![])
+                        .components(|components| add_chunk_buttons(components, !rest.is_empty(), locale))
+                }).await?;
+                self.hold_continuation(msgcomponent.message.id, rest);
+            },
+            ComponentAction::Continue => {
+                let rest = self.take_continuation(msgcomponent.message.id);
+                // Whether or not there's more to show, this button has done its job.
+                msgcomponent.edit_original_interaction_response(ctx, |m| m.components(|components| components)).await?;
+                let Some(mut rest) = rest else {
+                    log::info!("Nothing left to continue for {:?}.", msgcomponent.message.id);
+                    return Err(BotError::UserInput(Text::ReplyExpired.get(locale).into()));
+                };
+                let next = rest.remove(0);
+                let ephemeral = msgcomponent.message.flags.is_some_and(|flags| flags.contains(MessageFlags::EPHEMERAL));
+                let (next, more) = (&next, !rest.is_empty());
+                let sent = with_discord_retries("continue_chunk", move || msgcomponent.create_followup_message(ctx, move |m| {
+                    m
+                        .content(next.content.as_str())
+                        .add_embeds(next.embeds())
+                        .ephemeral(ephemeral)
+                        .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
+                        .components(|components| add_chunk_buttons(components, more, locale))
+                })).await;
+                let message = match sent {
+                    Ok(message) => message,
+                    Err(e) => {
+                        // The button's gone, so say where the reply ends instead of leaving it hanging.
+                        log::error!("Failed to send the chunk after {:?}, with {} more after it. Error: {e:?}", msgcomponent.message.id, rest.len());
+                        return Err(BotError::UserInput(Text::ReplyTruncated.get(locale).into()));
+                    },
+                };
+                self.hold_continuation(message.id, rest);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Holds back the `rest` of a reply until the continue button on `message_id` is clicked. Also
+    /// forgets anything that's been held back for too long.
+    fn hold_continuation(&self, message_id: MessageId, rest: Vec<ReplyChunk>) {
+        let now = Instant::now();
+        let mut continuations = self.continuations.lock();
+        continuations.retain(|_, (held_at, _)| now.saturating_duration_since(*held_at) < CONTINUATION_TTL);
+        if !rest.is_empty() {
+            continuations.insert(message_id, (now, rest));
+        }
+    }
+
+    /// What's left of the reply continued from `message_id`, unless it's expired.
+    fn take_continuation(&self, message_id: MessageId) -> Option<Vec<ReplyChunk>> {
+        let (held_at, rest) = self.continuations.lock().remove(&message_id)?;
+        if held_at.elapsed() >= CONTINUATION_TTL {
+            return None;
+        }
+        Some(rest).filter(|rest| !rest.is_empty())
+    }
+
+    /// Keeps `failed` for the resend button on the error sent as `message_id`. Also forgets any
+    /// that have been kept for too long.
+    fn hold_failed_prompt(&self, message_id: MessageId, failed: FailedPrompt) {
+        let mut failed_prompts = self.failed_prompts.lock();
+        failed_prompts.retain(|_, failed| failed.failed_at.elapsed() < FAILED_PROMPT_TTL);
+        failed_prompts.insert(message_id, failed);
+    }
+
+    /// The prompt that failed with the error sent as `message_id`, if `user_id` sent it. It's kept
+    /// after this, in case the modal is dismissed and opened again.
+    fn failed_prompt(&self, message_id: MessageId, user_id: UserId) -> Option<FailedPrompt> {
+        self.failed_prompts.lock().get(&message_id)
+            .filter(|failed| failed.user_id == user_id && failed.failed_at.elapsed() < FAILED_PROMPT_TTL)
+            .cloned()
+    }
+
+    /// Answers a click on an error's resend button with the compose modal, filled in with the prompt
+    /// that failed. The modal has to be the first response, so nothing's deferred.
+    async fn open_resend_modal(&self, ctx: &Context, msgcomponent: &MessageComponentInteraction, locale: Locale) -> Result<(), BotError> {
+        let Some(failed) = self.failed_prompt(msgcomponent.message.id, msgcomponent.user.id) else {
+            log::info!("Nothing for {:?} to resend from {:?}.", msgcomponent.user.id, msgcomponent.message.id);
+            msgcomponent.create_interaction_response(ctx, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|data| data.content(Text::ResendExpired.get(locale)).ephemeral(true))
+            }).await?;
+            return Ok(());
+        };
+        msgcomponent.create_interaction_response(ctx, |response| {
+            response
+                .kind(InteractionResponseType::Modal)
+                .interaction_response_data(|data| build_compose_modal(data, failed.model.as_deref(), Some(failed.prompt.as_str())))
+        }).await?;
+        Ok(())
+    }
+
+    /// Stops the reply streaming in response to `message_id`, if `user_id` is the one who asked.
+    fn stop_stream(&self, message_id: MessageId, user_id: UserId) -> Result<(), BotError> {
+        match self.cancellations.lock().get(&message_id) {
+            Some((author_id, cancel)) if *author_id == user_id => {
+                cancel.cancel();
+                Ok(())
+            },
+            Some(_) => {
+                log::warn!("User {user_id:?} tried to stop someone else's reply to {message_id:?}.");
+                Err(BotError::UserInput("Only whoever asked can stop this reply.".into()))
+            },
+            None => {
+                log::info!("Reply to {message_id:?} isn't streaming anymore. Nothing to stop.");
+                Ok(())
+            },
+        }
+    }
+
+    async fn handle_modal_and_errors(&self, ctx: Context, submission: ModalSubmitInteraction) {
+        log::info!("BEGIN ui=discord_modalsub interaction={submission:?}");
+        let interaction_id = submission.id;
+        match self.handle_modal(&ctx, &submission).await {
+            Ok(_) => {
+                log::info!("COMPLETE ui=discord_modalsub interaction={interaction_id:?} outcome=success");
+            },
+            Err(e0) => {
+                self.with_metrics(|metrics| metrics.record_error("discord_modalsub"));
+                let user_error = e0.is_user_error();
+                let locale = Locale::from_discord(submission.locale.as_str());
+                let model = parse_compose_modal_id(submission.data.custom_id.as_str()).flatten();
+                let failed_prompt = FailedPrompt::new(submission.user.id, model, modal_prompt(&submission));
+                let resend = failed_prompt.is_some();
+                match submission.create_followup_message(ctx, |m| {
+                    m.content(e0.user_message(locale));
+                    if resend {
+                        m.components(|components| add_resend_button(components, locale));
+                    }
+                    m
+                }).await {
+                    Ok(message) => {
+                        if let Some(failed_prompt) = failed_prompt {
+                            self.hold_failed_prompt(message.id, failed_prompt);
+                        }
+                        log::error!("COMPLETE ui=discord_modalsub interaction={interaction_id:?} outcome=error error={e0:?} user_error={user_error}");
+                    },
+                    Err(e1) => {
+                        log::error!("COMPLETE ui=discord_modalsub interaction={interaction_id:?} outcome=error primary_error={e0:?} secondary_error={e1:?} user_error={user_error}");
+                    },
+                }
+            },
+        }
+    }
+
+    /// Answers the prompt written in a compose modal.
+    async fn handle_modal(&self, ctx: &Context, submission: &ModalSubmitInteraction) -> Result<(), BotError> {
+        let locale = Locale::from_discord(submission.locale.as_str());
+        let deferral = submission.create_interaction_response(ctx, |response| {
+            response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        }).await;
+        if let Err(e) = deferral {
+            log::error!("Modal submission failed to be deferred. Error: {e:?}");
+            return Err(e.into());
+        }
+
+        let Some(model) = parse_compose_modal_id(submission.data.custom_id.as_str()) else {
+            log::warn!("Unknown modal `{}`.", submission.data.custom_id);
+            return Err(BotError::Internal);
+        };
+
+        let key = HistoryKey::resolve(ctx, submission.guild_id, submission.channel_id, submission.user.id).await;
+        if !self.cfg.is_channel_allowed(key.channel_id) {
+            log::info!("Ignoring modal in {:?}, which isn't an allowed channel.", submission.channel_id);
+            return Err(BotError::UserInput(Text::NotEnabledHere.get(locale).into()));
+        }
+        let roles = submission.member.as_ref().map(|member| member.roles.as_slice());
+        if !self.cfg.is_allowed(submission.user.id, roles) {
+            log::warn!("User {:?} is not allowed to use the bot.", submission.user.id);
+            return Err(BotError::UserInput(Text::NotAuthorized.get(locale).into()));
+        }
+        self.check_cooldown(submission.user.id)?;
+
+        let prompt = modal_prompt(submission);
+        if prompt.is_empty() {
+            log::warn!("A prompt is needed to give to the AI.");
+            return Err(BotError::UserInput("A prompt is needed to give to the AI.".into()));
+        }
+        validate_prompt_length(prompt, self.cfg.max_prompt_chars)?;
+
+        let (model, options) = self.resolve_options(key, RequestedOptions { model, ..RequestedOptions::default() })?;
+        let format = self.reply_format(key.guild_id, None)?;
+        let (retry_tx, retry_rx) = watch::channel(0);
+        let request = ChatRequest { key, user_name: submission.user.name.as_str(), model, prompt, images: vec![], options, reply_chain: None, retry_notices: Some(retry_tx), fallback: false };
+        let notice = RetryNotice::Interaction { token: submission.token.as_str() };
+        let response = with_retry_notices(ctx, notice, locale, retry_rx, self.run_chat(&request, None)).await?;
+
+        let (first, rest) = split_chunks(format_reply_chunks(format, prompt, response.as_str(), request.model.as_str(), None));
+        let (first, more) = (&first, !rest.is_empty());
+        let message = with_discord_retries("modal_followup", move || submission.create_followup_message(ctx, move |m| {
+            m
+                .content(first.content.as_str())
+                .add_embeds(first.embeds())
+                .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
+                .components(|components| add_chunk_buttons(components, more, locale))
+        })).await?;
+        self.hold_continuation(message.id, rest);
+        Ok(())
+    }
+
+    async fn handle_appcomm_and_errors(&self, ctx: Context, appcommand: ApplicationCommandInteraction) {
+        log::info!("BEGIN ui=discord_appcomm interaction={appcommand:?}");
+        let interaction_id = appcommand.id;
+        match self.handle_appcomm(&ctx, &appcommand).await {
+            Ok(_) => {
+                log::info!("COMPLETE ui=discord_appcomm message={interaction_id:?} outcome=success");
+            },
+            Err(e0) => {
+                self.with_metrics(|metrics| metrics.record_error("discord_appcomm"));
+                let user_error = e0.is_user_error();
+                let locale = Locale::from_discord(appcommand.locale.as_str());
+                let failed_prompt = if appcommand.data.name == "chat" {
+                    let str_option = |name: &str| appcommand.data.options.iter().find(|o| o.name == name)
+                        .and_then(|o| o.value.as_ref())
+                        .and_then(|v| v.as_str());
+                    FailedPrompt::new(appcommand.user.id, str_option("model"), str_option("prompt").unwrap_or(""))
+                } else {
+                    None
+                };
+                let (ctx, appcommand, text, resend) = (&ctx, &appcommand, e0.user_message(locale), failed_prompt.is_some());
+                match with_discord_retries("appcomm_error", move || appcommand.create_followup_message(ctx, move |m| {
+                    m.content(text);
+                    if resend {
+                        m.components(|components| add_resend_button(components, locale));
+                    }
+                    m
+                })).await {
+                    Ok(message) => {
+                        if let Some(failed_prompt) = failed_prompt {
+                            self.hold_failed_prompt(message.id, failed_prompt);
+                        }
+                        log::error!("COMPLETE ui=discord_appcomm interaction={interaction_id:?} outcome=error error={e0:?} user_error={user_error}");
+                    },
+                    Err(e1) => {
+                        log::error!("COMPLETE ui=discord_appcomm interaction={interaction_id:?} outcome=error primary_error={e0:?} secondary_error={e1:?} user_error={user_error}");
+                    },
+                }
+            },
+        }
+    }
+
+    async fn handle_appcomm(&self, ctx: &Context, appcommand: &ApplicationCommandInteraction) -> Result<(), BotError> {
+        let locale = Locale::from_discord(appcommand.locale.as_str());
+        // Discord requires the deferral and the followups to agree on whether they're ephemeral.
+        let private = appcommand.data.options.iter().find(|o| o.name == "private")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let ephemeral = ["usage", "config", "help", "clear", "reset", "setmodel", "persona", "export", "context", "stop_generation", "resume"].contains(&appcommand.data.name.as_str()) || (appcommand.data.name == "chat" && private);
+
+        // Checked before deferring, so that the refusal is only shown to whoever asked.
+        if !self.cfg.allowed_channels.is_empty() {
+            let key = HistoryKey::resolve(ctx, appcommand.guild_id, appcommand.channel_id, appcommand.user.id).await;
+            if !self.cfg.is_channel_allowed(key.channel_id) {
+                log::info!("Ignoring command in {:?}, which isn't an allowed channel.", appcommand.channel_id);
+                appcommand.create_interaction_response(ctx, |response| {
+                    response
+                        .kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|data| data.content(Text::NotEnabledHere.get(locale)).ephemeral(true))
+                }).await?;
+                return Ok(());
+            }
+        }
+
+        // Modals have to be the first response, so this can't be deferred. Everything is checked once
+        // the modal is submitted instead.
+        if appcommand.data.name == "compose" {
+            let model = appcommand.data.options.iter().find(|o| o.name == "model")
+                .and_then(|o| o.value.as_ref())
+                .and_then(|v| v.as_str());
+            appcommand.create_interaction_response(ctx, |response| {
+                response
+                    .kind(InteractionResponseType::Modal)
+                    .interaction_response_data(|data| build_compose_modal(data, model, None))
+            }).await?;
+            return Ok(());
+        }
+
+        // Deferring already shows Discord's own "thinking" state, so there's no typing indicator here.
+        let deferral = appcommand.create_interaction_response(ctx, |response| {
+            response
+                .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|data| data.ephemeral(ephemeral))
+        }).await;
+        if let Err(e) = deferral {
+            log::error!("Application command failed to be deferred. Error: {e:?}");
+            return Err(e.into());
+        }
+
+        let roles = appcommand.member.as_ref().map(|member| member.roles.as_slice());
+        if !self.cfg.is_allowed(appcommand.user.id, roles) {
+            log::warn!("User {:?} is not allowed to use the bot.", appcommand.user.id);
+            return Err(BotError::UserInput(Text::NotAuthorized.get(locale).into()));
+        }
+
+        if ["chat", "regenerate", "image", "pins", "summarize"].contains(&appcommand.data.name.as_str()) {
+            self.check_cooldown(appcommand.user.id)?;
+        }
+
+        let key = HistoryKey::resolve(ctx, appcommand.guild_id, appcommand.channel_id, appcommand.user.id).await;
+
+        if appcommand.data.name == "clear" {
+            appcommand.create_followup_message(ctx, |m| {
+                m
+                    .content(Text::ClearConfirmation.get(locale))
+                    .ephemeral(true)
+                    .components(|components| add_clear_confirmation_buttons(components, appcommand.user.id, locale))
+            }).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "summarize" {
+            let summary = self.summarize(key).await?;
+            let (first, rest) = split_reply(format!("Replaced your chat history here with this summary. Use `/undo` to bring it back.\n\n{summary}").as_str());
+            let message = appcommand.create_followup_message(ctx, |m| m.content(first.content).components(|components| add_chunk_buttons(components, !rest.is_empty(), locale))).await?;
+            self.hold_continuation(message.id, rest);
+            return Ok(());
+        }
+
+        if appcommand.data.name == "export" {
+            match self.export(key) {
+                Some(export) => appcommand.create_followup_message(ctx, |m| m.add_file(export_attachment(export)).ephemeral(true)).await?,
+                None => appcommand.create_followup_message(ctx, |m| m.content(Text::NothingToExport.get(locale)).ephemeral(true)).await?,
+            };
+            return Ok(());
+        }
+
+        if appcommand.data.name == "context" {
+            match self.context(key) {
+                Some(context) => appcommand.create_followup_message(ctx, |m| m.embed(|embed| {
+                    embed
+                        .title("Chat history")
+                        .description(context.description)
+                        .footer(|footer| footer.text(format!("About {} tokens across {} turns", context.tokens, context.turns)))
+                }).ephemeral(true)).await?,
+                None => appcommand.create_followup_message(ctx, |m| m.content(Text::NothingRemembered.get(locale)).ephemeral(true)).await?,
+            };
+            return Ok(());
+        }
+
+        if appcommand.data.name == "pins" {
+            let count = self.load_pins(ctx, appcommand.channel_id, key).await?;
+            appcommand.create_followup_message(ctx, |m| m.content(pins_loaded_reply(count)).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "persona" {
+            let persona = appcommand.data.options.iter().find(|o| o.name == "prompt").ok_or(BotError::Internal)?
+                .value.as_ref().and_then(|v| v.as_str()).ok_or(BotError::Internal)?;
+            let reply = self.set_persona(key, persona)?;
+            appcommand.create_followup_message(ctx, |m| m.content(reply).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "setmodel" {
+            let model = appcommand.data.options.iter().find(|o| o.name == "model").ok_or(BotError::Internal)?
+                .value.as_ref().and_then(|v| v.as_str()).ok_or(BotError::Internal)?;
+            self.set_model(key, model)?;
+            appcommand.create_followup_message(ctx, |m| m.content(set_model_reply(model)).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "undo" {
+            let reply = undo_reply(self.undo(key).await?, locale);
+            appcommand.create_followup_message(ctx, |m| m.content(reply)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "regenerate" {
+            let (prompt, response) = self.regenerate(key).await?;
+            let (first, rest) = split_reply(format_reply(prompt.as_str(), response.as_str()).as_str());
+            let message = appcommand.create_followup_message(ctx, |m| {
+                m
+                    .content(first.content)
+                    .components(|components| add_chunk_buttons(components, !rest.is_empty(), locale))
+            }).await?;
+            self.hold_continuation(message.id, rest);
+            return Ok(());
+        }
+
+        if appcommand.data.name == "image" {
+            let prompt = appcommand.data.options.iter().find(|o| o.name == "prompt").ok_or(BotError::Internal)?
+                .value.as_ref().and_then(|v| v.as_str()).ok_or(BotError::Internal)?;
+            let size = appcommand.data.options.iter().find(|o| o.name == "size")
+                .and_then(|o| o.value.as_ref())
+                .and_then(|v| v.as_str())
+                .unwrap_or("1024x1024");
+            let urls = self.generate_image(prompt, size).await?;
+            appcommand.create_followup_message(ctx, |m| {
+                m.content(format_reply(prompt, ""));
+                for url in urls {
+                    m.embed(|embed| embed.image(url));
+                }
+                m
+            }).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "ping" {
+            let uptime = format_duration(chrono::Duration::from_std(self.started_at.elapsed()).unwrap_or_else(|_| chrono::Duration::max_value()));
+            let latency = match gateway_latency(ctx).await {
+                Some(latency) => format!("{}ms", latency.as_millis()),
+                None => "Not measured yet".to_owned(),
+            };
+            let openai = self.ping_openai().await;
+            appcommand.create_followup_message(ctx, |m| m.embed(|embed| {
+                embed
+                    .title("Pong!")
+                    .field("Uptime", uptime, true)
+                    .field("Discord gateway latency", latency, true)
+                    .field("OpenAI", openai, true)
+            })).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "help" {
+            let help = help_text(self.cfg.classic_commands.then_some(self.cfg.command_prefix.as_str()));
+            appcommand.create_followup_message(ctx, |m| m.embed(|embed| embed.title("Commands").description(help)).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "reset" {
+            let scope = appcommand.data.options.iter().find(|o| o.name == "scope")
+                .and_then(|o| o.value.as_ref())
+                .and_then(|v| v.as_str())
+                .unwrap_or("me");
+            let reply = if scope == "server" {
+                let Some(guild_id) = appcommand.guild_id else {
+                    log::warn!("Server reset used outside of a guild.");
+                    return Err(BotError::UserInput("Only servers can be reset.".into()));
+                };
+                if !is_guild_manager(appcommand) {
+                    log::warn!("User {:?} is not allowed to reset guild {guild_id:?}.", appcommand.user.id);
+                    return Err(BotError::UserInput("Only server managers can reset the server.".into()));
+                }
+                self.reset_guild(guild_id).await?;
+                Text::ResetGuild.get(locale)
+            } else {
+                self.clear_all(appcommand.user.id).await?;
+                Text::ResetUser.get(locale)
+            };
+            appcommand.create_followup_message(ctx, |m| m.content(reply).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "config" {
+            let report = self.configure_guild(appcommand)?;
+            appcommand.create_followup_message(ctx, |m| m.content(report).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "models" {
+            let models = self.available_models().await;
+            let listing = models.iter().map(|model| format!("`{model}`")).collect::<Vec<_>>().join("\n");
+            appcommand.create_followup_message(ctx, |m| m.embed(|embed| embed.title("Available models").description(listing))).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "stop_generation" || appcommand.data.name == "resume" {
+            let reply = self.set_halted(appcommand.user.id, appcommand.data.name == "stop_generation")?;
+            appcommand.create_followup_message(ctx, |m| m.content(reply).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name == "usage" {
+            let totals = self.usage.lock().get(&appcommand.user.id).copied().unwrap_or_default();
+            let UsageTotals { requests, tokens: TokenUsage { prompt_tokens, completion_tokens, total_tokens }, estimated_cost } = totals;
+            let report = format!("Requests: {requests}\nPrompt tokens: {prompt_tokens}\nCompletion tokens: {completion_tokens}\nTotal tokens: {total_tokens}\nEstimated cost: ${estimated_cost:.4}");
+            appcommand.create_followup_message(ctx, |m| m.content(report).ephemeral(true)).await?;
+            return Ok(());
+        }
+
+        if appcommand.data.name != "chat" {
+            return Ok(());
+        }
+
+        let prompt = appcommand.data.options.iter().find(|o| o.name == "prompt").ok_or(BotError::Internal)?
+            .value.as_ref().and_then(|v| v.as_str()).ok_or(BotError::Internal)?;
+        validate_prompt_length(prompt, self.cfg.max_prompt_chars)?;
+        let str_option = |name: &str| appcommand.data.options.iter().find(|o| o.name == name)
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str());
+        let f64_option = |name: &str| appcommand.data.options.iter().find(|o| o.name == name)
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_f64());
+        let i64_option = |name: &str| appcommand.data.options.iter().find(|o| o.name == name)
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_i64());
+        let bool_option = |name: &str| appcommand.data.options.iter().find(|o| o.name == name)
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_bool());
+        let requested = RequestedOptions {
+            model: str_option("model"),
+            temperature: f64_option("temperature"),
+            top_p: f64_option("top_p"),
+            max_tokens: i64_option("max_tokens"),
+            system_prompt: str_option("system"),
+            stop: str_option("stop"),
+            presence_penalty: f64_option("presence_penalty"),
+            frequency_penalty: f64_option("frequency_penalty"),
+            choices: i64_option("choices"),
+            seed: i64_option("seed"),
+            logprobs: i64_option("logprobs"),
+            language: str_option("language"),
+            json: bool_option("json"),
+        };
+        let warning = sampling_warning(&requested);
+        let (model, options) = self.resolve_options(key, requested)?;
+        // JSON is always shown in a code block.
+        let format = if options.json { ReplyFormat::Code } else { self.reply_format(key.guild_id, str_option("format"))? };
+        let image = appcommand.data.options.iter().find(|o| o.name == "image")
+            .and_then(|o| o.resolved.as_ref())
+            .and_then(|v| match v {
+                CommandDataOptionValue::Attachment(attachment) => Some(attachment),
+                _ => None,
+            });
+        let images: Vec<_> = match image {
+            Some(image) if !is_image_attachment(image) => {
+                log::warn!("Attachment `{}` is not an image.", image.filename);
+                return Err(BotError::UserInput(format!("That attachment isn't an image. Supported formats are: {}.", IMAGE_EXTENSIONS.join(", "))));
+            },
+            image => image.map(|image| image.url.clone()).into_iter().collect(),
+        };
+        validate_images(model.as_str(), images.as_slice())?;
+
+        let start_thread = appcommand.data.options.iter().find(|o| o.name == "thread")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let started_thread = start_thread && key.thread_id.is_none() && key.guild_id.is_some() && !ephemeral;
+        let key = if started_thread {
+            // The deferred response is the message the thread hangs off of.
+            let response_message = appcommand.get_interaction_response(ctx).await.map_err(|e| {
+                log::error!("Failed to get interaction response to start a thread from. Error: {e:?}");
+                BotError::from(e)
+            })?;
+            let thread = appcommand.channel_id.create_public_thread(ctx, response_message.id, |thread| thread.name(thread_name(prompt))).await.map_err(|e| {
+                log::error!("Failed to start a thread. Error: {e:?}");
+                BotError::UserInput("Couldn't start a thread here. Do I have permission to create threads?".into())
+            })?;
+            key.in_thread(thread.id)
+        } else {
+            key
+        };
+
+        let (retry_tx, retry_rx) = watch::channel(0);
+        let request = ChatRequest { key, user_name: appcommand.user.name.as_str(), model, prompt, images, options, reply_chain: None, retry_notices: Some(retry_tx), fallback: bool_option("fallback").unwrap_or(false) };
+        // A thread hangs off of the deferred response, so it can't be replaced with a notice.
+        let notice = if started_thread { RetryNotice::None } else { RetryNotice::Interaction { token: appcommand.token.as_str() } };
+        let chat = with_retry_notices(ctx, notice, locale, retry_rx, self.run_chat_choices(&request, None));
+        if request.options.n > 1 {
+            let ChatReplies { choices: replies, model: replied, .. } = chat.await?;
+            let note = fallback_note(request.model.as_str(), replied.as_str(), locale);
+            // Embeds share a 6000 character budget per message, on top of each one's own limit.
+            let max_chars = (MAX_CHOICE_CHARS / replies.len()).min(MAX_EMBED_DESCRIPTION_CHARS);
+            appcommand.create_followup_message(ctx, |m| {
+                m
+                    .content(add_note(format_reply(prompt, Text::PickReply.get(locale)), note.as_deref()))
+                    .ephemeral(ephemeral)
+                    .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true));
+                for (idx, reply) in replies.iter().enumerate() {
+                    m.embed(|embed| embed.title(format!("Reply {}", idx + 1)).description(truncate_chars(reply.trim(), max_chars)));
+                }
+                m.components(|components| components.create_action_row(|row| row.create_select_menu(|menu| {
+                    menu
+                        .custom_id(ComponentAction::Pick.custom_id())
+                        .placeholder(Text::PickReplyPlaceholder.get(locale))
+                        .options(|menu_options| {
+                            for idx in 0..replies.len() {
+                                menu_options.create_option(|option| option.label(format!("Reply {}", idx + 1)).value(idx));
+                            }
+                            menu_options
+                        })
+                })))
+            }).await?;
+            if let Some(warning) = warning {
+                appcommand.create_followup_message(ctx, |m| m.content(warning).ephemeral(true)).await?;
+            }
+            return Ok(());
+        }
+
+        let ChatReplies { mut choices, logprobs, model: replied } = chat.await?;
+        let gpt_response = choices.swap_remove(0);
+        let note = fallback_note(request.model.as_str(), replied.as_str(), locale);
+        let logprobs = logprobs.filter(|logprobs| !logprobs.is_empty()).map(|logprobs| render_logprobs(logprobs.as_slice()));
+
+        let (first, rest) = split_chunks(format_reply_chunks(format, prompt, gpt_response.as_str(), replied.as_str(), note.as_deref()));
+        let (first, more, logprobs) = (&first, !rest.is_empty(), logprobs.as_deref());
+        let (text, embeds) = (first.content.as_str(), || first.embeds());
+        // Shares the message's embed budget with the reply's own embed, if it has one.
+        const LOGPROBS_TITLE: &str = "Top tokens";
+        let max_logprobs_chars = MAX_EMBED_DESCRIPTION_CHARS.min(MAX_EMBED_TOTAL_CHARS - first.embed_chars() - LOGPROBS_TITLE.len());
+        let response_result = with_discord_retries("appcomm_followup", move || appcommand.create_followup_message(ctx, move |m| {
+            m
+                .content(text)
+                .add_embeds(embeds())
+                .ephemeral(ephemeral)
+                .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
+                .components(|components| add_chunk_buttons(components, more, locale));
+            if let Some(logprobs) = logprobs {
+                m.embed(|embed| embed.title(LOGPROBS_TITLE).description(truncate_chars(logprobs, max_logprobs_chars)));
+            }
+            m
+        })).await;
+        let mut expired = false;
+        let response_result = match response_result {
+            // Ephemeral replies can't be posted to the channel without giving them away.
+            Err(e) if is_expired_interaction(&e) && !ephemeral => {
+                log::warn!("FALLBACK ui=discord_appcomm interaction={:?} reason=interaction_expired", appcommand.id);
+                expired = true;
+                with_discord_retries("appcomm_fallback", move || appcommand.channel_id.send_message(ctx, move |m| {
+                    m
+                        .content(format!("{}\n{text}", appcommand.user.mention()))
+                        .add_embeds(embeds())
+                        .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().users([appcommand.user.id]))
+                        .components(|components| add_chunk_buttons(components, more, locale))
+                })).await
+            },
+            response_result => response_result,
+        };
+        match response_result {
+            Ok(message) => self.hold_continuation(message.id, rest),
+            Err(e) => {
+                log::error!("Something went wrong sending the message... Error: {e:?}");
+                return Err(e.into());
+            },
+        }
+        if let Some(warning) = warning.filter(|_| !expired) {
+            appcommand.create_followup_message(ctx, |m| m.content(warning).ephemeral(true)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message_and_errors(&self, ctx: Context, msg: Message) {
+        log::info!("BEGIN ui=discord_classic message={msg:?}");
+        let msg_id = msg.id;
+        match self.handle_message(&ctx, &msg).await {
+            Ok(_) => {
+                log::info!("COMPLETE ui=discord_classic message={msg_id:?} outcome=success");
+            },
+            Err(e0) => {
+                self.with_metrics(|metrics| metrics.record_error("discord_classic"));
+                let user_error = e0.is_user_error();
+                let locale = Locale::of_guild(&ctx, msg.guild_id);
+                let failed_prompt = split_classic_command(msg.content.as_str(), self.cfg.command_prefix.as_str())
+                    .filter(|(command, _)| *command == "chat")
+                    .and_then(|(_, args)| {
+                        let (model, prompt) = classic_chat_prompt(args);
+                        FailedPrompt::new(msg.author.id, model, prompt)
+                    });
+                let (ctx, msg, text, resend) = (&ctx, &msg, e0.user_message(locale), failed_prompt.is_some());
+                match with_discord_retries("classic_error", move || msg.channel_id.send_message(ctx, move |m| {
+                    m.content(text).reference_message(msg);
+                    if resend {
+                        m.components(|components| add_resend_button(components, locale));
+                    }
+                    m
+                })).await {
+                    Ok(message) => {
+                        if let Some(failed_prompt) = failed_prompt {
+                            self.hold_failed_prompt(message.id, failed_prompt);
+                        }
+                        log::error!("COMPLETE ui=discord_classic message={msg_id:?} outcome=error error={e0:?} user_error={user_error}");
+                    },
+                    Err(e1) => {
+                        log::error!("COMPLETE ui=discord_classic message={msg_id:?} outcome=error primary_error={e0:?} secondary_error={e1:?} user_error={user_error}");
+                    },
+                }
+            },
+        }
+    }
+
+    /// Keeps the reply to `msg`, sent in `reply_channel`, in sync with a streaming completion, editing
+    /// at most once every [`STREAM_EDIT_INTERVAL`]. Starts by overwriting `first` if there is one,
+    /// spilling into new messages once the text no longer fits. The first message has a button to stop
+    /// the reply until it's done.
+    async fn stream_into_messages(ctx: &Context, msg: &Message, locale: Locale, reply_channel: ChannelId, prompt: &str, first: Option<Message>, mut progress: watch::Receiver<String>) {
+        let mut sent: Vec<_> = first.into_iter().collect();
+        let mut rendered: Vec<String> = vec![];
+        let stop_id = ComponentAction::Stop(msg.id).custom_id();
+        let add_stop_button = |components: &mut CreateComponents| {
+            components.create_action_row(|row| row.create_button(|button| button.custom_id(stop_id.as_str()).label(Text::StopButton.get(locale)).style(ButtonStyle::Danger)));
+        };
+        let mut stop_shown = false;
+        // Set once a chunk couldn't be sent, after which nothing more is sent, so that the reply
+        // doesn't continue with a gap in it.
+        let mut truncated = false;
+        loop {
+            let done = progress.changed().await.is_err();
+            let text = progress.borrow_and_update().clone();
+            if !text.trim().is_empty() {
+                let chunks = chunk_message(format_reply(prompt, text.as_str()).as_str());
+                for (idx, chunk) in chunks.into_iter().enumerate() {
+                    if rendered.get(idx) == Some(&chunk) {
+                        continue;
+                    }
+                    let with_stop = idx == 0 && !done && !stop_shown;
+                    if let Some(message) = sent.get_mut(idx) {
+                        let edit = message.edit(ctx, |m| {
+                            m.content(chunk.as_str());
+                            if with_stop {
+                                m.components(|components| {
+                                    add_stop_button(components);
+                                    components
+                                });
+                            }
+                            m
+                        }).await;
+                        match edit {
+                            Ok(()) => stop_shown |= with_stop,
+                            Err(e) => log::error!("Failed to edit streamed message. Continuing. Error: {e:?}"),
+                        }
+                    } else if truncated {
+                        break;
+                    } else {
+                        let text = chunk.as_str();
+                        let new_message = with_discord_retries("stream_chunk", move || reply_channel.send_message(ctx, move |msg_builder| {
+                            msg_builder
+                                .content(text)
+                                .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true));
+                            if reply_channel == msg.channel_id {
+                                msg_builder.reference_message(msg);
+                            }
+                            if with_stop {
+                                msg_builder.components(|components| {
+                                    add_stop_button(components);
+                                    components
+                                });
+                            }
+                            msg_builder
+                        })).await;
+                        stop_shown |= with_stop && new_message.is_ok();
+                        match new_message {
+                            Ok(new_message) => sent.push(new_message),
+                            Err(e) => {
+                                log::error!("Failed to send chunk {idx} of the streamed reply. Marking it truncated. Error: {e:?}");
+                                truncated = true;
+                                let marker = with_discord_retries("stream_truncated", move || reply_channel.say(ctx, Text::ReplyTruncated.get(locale))).await;
+                                if let Err(e) = marker {
+                                    log::error!("Failed to mark the streamed reply as truncated. Continuing. Error: {e:?}");
+                                }
+                                break;
+                            },
+                        }
+                    }
+                    if idx < rendered.len() {
+                        rendered[idx] = chunk;
+                    } else {
+                        rendered.push(chunk);
+                    }
+                }
+            }
+            if done {
+                break;
+            }
+            tokio::time::sleep(STREAM_EDIT_INTERVAL).await;
+        }
+
+        // Swap the stop button for the ones that go under a finished reply.
+        let last = sent.len().saturating_sub(1);
+        for (idx, message) in sent.iter_mut().enumerate() {
+            if idx != last && !(idx == 0 && stop_shown) {
+                continue;
+            }
+            let edit = message.edit(ctx, |m| m.components(|components| {
+                if idx == last {
+                    add_reply_buttons(components, locale);
+                }
+                components
+            })).await;
+            if let Err(e) = edit {
+                log::error!("Failed to update the buttons on a streamed message. Continuing. Error: {e:?}");
+            }
+        }
+    }
+
+    async fn handle_message(&self, ctx: &Context, msg: &Message) -> Result<(), BotError> {
+        if is_from_bot(&msg.author, ctx.cache.current_user_id()) {
+            return Ok(());
+        }
+        if is_missing_content(msg, ctx.cache.current_user_id()) && !self.warned_missing_content.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Got a message with no content in {:?}. Classic commands won't work until the Message Content intent is enabled under Bot > Privileged Gateway Intents in the Discord developer portal.",
+                msg.channel_id,
+            );
+        }
+        let Some((command, args)) = split_classic_command(msg.content.as_str(), self.cfg.command_prefix.as_str()) else {
+            return Ok(());
+        };
+        let known = match command {
+            "clear" | "clear-force" | "clear-all" | "regenerate" | "undo" | "transcribe" | "pins" | "export" | "context" | "summarize" | "help" | "stop_generation" | "resume" => args.is_empty(),
+            "chat" => true,
+            "setmodel" | "persona" => !args.is_empty(),
+            _ => false,
+        };
+        if !known {
+            return Ok(());
+        }
+
+        let key = HistoryKey::resolve(ctx, msg.guild_id, msg.channel_id, msg.author.id).await;
+        if !self.cfg.is_channel_allowed(key.channel_id) {
+            log::info!("Ignoring command in {:?}, which isn't an allowed channel.", msg.channel_id);
+            return Ok(());
+        }
+
+        let reaction = if self.cfg.progress_reactions { try_react(ctx, msg, PROCESSING_REACTION).await } else { None };
+        let result = self.handle_classic_command(ctx, msg, command, args, key).await;
+        if let Some(reaction) = reaction {
+            if let Err(e) = reaction.delete(ctx).await {
+                log::warn!("Failed to remove the processing reaction. Continuing. Error: {e:?}");
+            }
+            try_react(ctx, msg, if result.is_ok() { SUCCESS_REACTION } else { FAILURE_REACTION }).await;
+        }
+        result
+    }
+
+    /// Handles a classic command that's known and allowed in `key`'s channel.
+    async fn handle_classic_command(&self, ctx: &Context, msg: &Message, command: &str, args: &str, key: HistoryKey) -> Result<(), BotError> {
+        let locale = Locale::of_guild(ctx, msg.guild_id);
+
+        let roles = msg.member.as_ref().map(|member| member.roles.as_slice());
+        if !self.cfg.is_allowed(msg.author.id, roles) {
+            log::warn!("User {:?} is not allowed to use the bot.", msg.author.id);
+            return Err(BotError::UserInput(Text::NotAuthorized.get(locale).into()));
+        }
+
+        if ["chat", "regenerate", "transcribe", "pins", "summarize"].contains(&command) {
+            self.check_cooldown(msg.author.id)?;
+        }
+
+        if command == "stop_generation" || command == "resume" {
+            let reply = self.set_halted(msg.author.id, command == "stop_generation")?;
+            msg.reply(ctx, reply).await?;
+            return Ok(());
+        }
+
+        if command == "help" {
+            let help = help_text(Some(self.cfg.command_prefix.as_str()));
+            msg.channel_id.send_message(ctx, |m| m.embed(|embed| embed.title("Commands").description(help)).reference_message(msg)).await?;
+            return Ok(());
+        }
+
+        if command == "clear" {
+            msg.channel_id.send_message(ctx, |m| {
+                m
+                    .content(Text::ClearConfirmation.get(locale))
+                    .reference_message(msg)
+                    .components(|components| add_clear_confirmation_buttons(components, msg.author.id, locale))
+            }).await?;
+            return Ok(());
+        }
+
+        if command == "clear-force" {
+            self.clear(key).await?;
+            msg.reply(ctx, Text::HistoryCleared.get(locale)).await?;
+            return Ok(());
+        }
+
+        if command == "clear-all" {
+            self.clear_all(msg.author.id).await?;
+            msg.reply(ctx, Text::HistoryClearedEverywhere.get(locale)).await?;
+            return Ok(());
+        }
+
+        if command == "transcribe" {
+            let Some(attachment) = audio_attachment(msg) else {
+                log::warn!("Nothing to transcribe, since there's no audio attachment.");
+                return Err(BotError::UserInput(format!("Attach an audio file to transcribe. Supported formats are: {}.", AUDIO_EXTENSIONS.join(", "))));
+            };
+            let transcription = while_typing(ctx, msg.channel_id, self.transcribe(attachment)).await?;
+            let transcription = if transcription.trim().is_empty() { "(no speech was found)" } else { transcription.as_str() };
+            self.send_reply(ctx, msg, locale, msg.channel_id, text_chunks(transcription)).await?;
+            return Ok(());
+        }
+
+        if command == "summarize" {
+            let summary = while_typing(ctx, msg.channel_id, self.summarize(key)).await?;
+            let prefix = self.cfg.command_prefix.as_str();
+            self.send_reply(ctx, msg, locale, msg.channel_id, text_chunks(format!("Replaced your chat history here with this summary. Use `{prefix}undo` to bring it back.\n\n{summary}").as_str())).await?;
+            return Ok(());
+        }
+
+        if command == "export" {
+            match self.export(key) {
+                Some(export) => msg.channel_id.send_message(ctx, |m| m.add_file(export_attachment(export)).reference_message(msg)).await?,
+                None => msg.reply(ctx, Text::NothingToExport.get(locale)).await?,
+            };
+            return Ok(());
+        }
+
+        if command == "context" {
+            match self.context(key) {
+                Some(context) => msg.channel_id.send_message(ctx, |m| m.embed(|embed| {
+                    embed
+                        .title("Chat history")
+                        .description(context.description)
+                        .footer(|footer| footer.text(format!("About {} tokens across {} turns", context.tokens, context.turns)))
+                }).reference_message(msg)).await?,
+                None => msg.reply(ctx, Text::NothingRemembered.get(locale)).await?,
+            };
+            return Ok(());
+        }
+
+        if command == "pins" {
+            let count = while_typing(ctx, msg.channel_id, self.load_pins(ctx, msg.channel_id, key)).await?;
+            msg.reply(ctx, pins_loaded_reply(count)).await?;
+            return Ok(());
+        }
+
+        if command == "persona" {
+            let reply = self.set_persona(key, args)?;
+            msg.reply(ctx, reply).await?;
+            return Ok(());
+        }
+
+        if command == "setmodel" {
+            self.set_model(key, args)?;
+            msg.reply(ctx, set_model_reply(args)).await?;
+            return Ok(());
+        }
+
+        if command == "undo" {
+            let reply = undo_reply(self.undo(key).await?, locale);
+            msg.reply(ctx, reply).await?;
+            return Ok(());
+        }
+
+        if command == "regenerate" {
+            let (prompt, response) = while_typing(ctx, msg.channel_id, self.regenerate(key)).await?;
+            self.send_reply(ctx, msg, locale, msg.channel_id, text_chunks(format_reply(prompt.as_str(), response.as_str()).as_str())).await?;
+            return Ok(());
+        }
+
+        let (model, mut prompt) = split_classic_model(args);
+        let mut requested = RequestedOptions { model, ..RequestedOptions::default() };
+
+        // Options come before the prompt, as `name=value`.
+        let mut start_thread = false;
+        let mut requested_format = None;
+        let mut fallback = false;
+        while let Some((name, value, rest)) = split_classic_option(prompt) {
+            match name {
+                "max_tokens" => {
+                    let Ok(value) = value.parse() else {
+                        log::warn!("Max tokens should be a number. Found `{value}`.");
+                        return Err(BotError::UserInput(format!("Max tokens should be a number. Found `{value}`.")));
+                    };
+                    requested.max_tokens = Some(value);
+                },
+                "thread" => {
+                    let Ok(value) = value.parse() else {
+                        log::warn!("Thread should be `true` or `false`. Found `{value}`.");
+                        return Err(BotError::UserInput(format!("Thread should be `true` or `false`. Found `{value}`.")));
+                    };
+                    start_thread = value;
+                },
+                "stop" => {
+                    requested.stop = Some(value);
+                },
+                "seed" => {
+                    let Ok(value) = value.parse() else {
+                        log::warn!("Seed should be a whole number. Found `{value}`.");
+                        return Err(BotError::UserInput(format!("Seed should be a whole number. Found `{value}`.")));
+                    };
+                    requested.seed = Some(value);
+                },
+                "top_p" => {
+                    let Ok(value) = value.parse() else {
+                        log::warn!("Top p should be a number. Found `{value}`.");
+                        return Err(BotError::UserInput(format!("Top p should be a number. Found `{value}`.")));
+                    };
+                    requested.top_p = Some(value);
+                },
+                "language" => {
+                    requested.language = Some(value);
+                },
+                "format" => {
+                    requested_format = Some(value);
+                },
+                "json" => {
+                    let Ok(value) = value.parse() else {
+                        log::warn!("JSON should be `true` or `false`. Found `{value}`.");
+                        return Err(BotError::UserInput(format!("JSON should be `true` or `false`. Found `{value}`.")));
+                    };
+                    requested.json = Some(value);
+                },
+                "fallback" => {
+                    let Ok(value) = value.parse() else {
+                        log::warn!("Fallback should be `true` or `false`. Found `{value}`.");
+                        return Err(BotError::UserInput(format!("Fallback should be `true` or `false`. Found `{value}`.")));
+                    };
+                    fallback = value;
+                },
+                _ => break,
+            }
+            prompt = rest;
+        }
+        let (model, options) = self.resolve_options(key, requested)?;
+        // JSON is always shown in a code block.
+        let format = if options.json { ReplyFormat::Code } else { self.reply_format(key.guild_id, requested_format)? };
+        let images: Vec<_> = msg.attachments.iter()
+            .filter(|attachment| is_image_attachment(attachment))
+            .map(|attachment| attachment.url.clone())
+            .collect();
+        validate_images(model.as_str(), images.as_slice())?;
+
+        // Without a written prompt, a voice message can stand in for one.
+        let transcription;
+        let prompt = match (prompt.is_empty(), audio_attachment(msg)) {
+            (false, _) => prompt,
+            (true, Some(attachment)) => {
+                transcription = while_typing(ctx, msg.channel_id, self.transcribe(attachment)).await?;
+                transcription.trim()
+            },
+            (true, None) => "",
+        };
+        if prompt.is_empty() {
+            log::warn!("A prompt is needed to give to the AI.");
+            return Err(BotError::UserInput("A prompt is needed to give to the AI.".into()));
+        }
+        validate_prompt_length(prompt, self.cfg.max_prompt_chars)?;
+
+        let referenced_message = referenced_message(ctx, msg).await;
+        let reply_chain = match referenced_message.as_ref() {
+            Some(referenced_message) if referenced_message.author.id == ctx.cache.current_user_id() && self.cfg.reply_chain_depth > 0 => {
+                reply_chain(ctx, referenced_message, self.cfg.reply_chain_depth).await
+            },
+            _ => None,
+        };
+        let prompt_with_context = match referenced_message {
+            // Skip bots, so that we don't end up feeding our own replies back in a loop.
+            Some(referenced_message) if !referenced_message.author.bot && !referenced_message.content.is_empty() => {
+                let author = referenced_message.author.name.as_str();
+                let content: String = referenced_message.content.chars().take(REFERENCED_MESSAGE_LIMIT).collect();
+                format!("Context, from a message by {author}:\n{content}\n\n{prompt}")
+            },
+            _ => prompt.to_owned(),
+        };
+
+        let (key, reply_channel) = if start_thread && key.thread_id.is_none() && key.guild_id.is_some() {
+            let thread = msg.channel_id.create_public_thread(ctx, msg.id, |thread| thread.name(thread_name(prompt))).await.map_err(|e| {
+                log::error!("Failed to start a thread. Error: {e:?}");
+                BotError::UserInput("Couldn't start a thread here. Do I have permission to create threads?".into())
+            })?;
+            (key.in_thread(thread.id), thread.id)
+        } else {
+            (key, msg.channel_id)
+        };
+
+        // Only fall back to a message if we can't even show that we're typing, and there's no reaction
+        // showing progress already. `while_typing` keeps the indicator up from here on; this broadcast
+        // is only to find out whether it can.
+        let in_progress_message = match reply_channel.broadcast_typing(ctx).await {
+            Ok(()) => None,
+            Err(e) if self.cfg.progress_reactions => {
+                log::warn!("Failed to broadcast typing. Continuing. Error: {e:?}");
+                None
+            },
+            Err(e) => {
+                log::warn!("Failed to broadcast typing. Falling back to a message. Error: {e:?}");
+                let in_progress_message = msg.reply(ctx, Text::Thinking.get(locale)).await.ok();
+                if in_progress_message.is_none() {
+                    log::error!("Failed to send in progress message. Continuing.");
+                }
+                in_progress_message
+            },
+        };
+
+        let (retry_tx, retry_rx) = watch::channel(0);
+        let request = ChatRequest { key, user_name: msg.author.name.as_str(), model, prompt: prompt_with_context.as_str(), images, options, reply_chain, retry_notices: Some(retry_tx), fallback };
+        let notice = RetryNotice::Channel { channel_id: reply_channel, msg };
+
+        // Streamed replies are written out as plain text while they come in.
+        if self.cfg.stream_responses && format == ReplyFormat::Plain {
+            let (progress_tx, progress_rx) = watch::channel(String::new());
+            let cancel = CancellationToken::new();
+            self.cancellations.lock().insert(msg.id, (msg.author.id, cancel.clone()));
+            let stream = StreamTarget { progress: progress_tx, cancel };
+            let (response, ()) = while_typing(ctx, reply_channel, async {
+                tokio::join!(
+                    with_retry_notices(ctx, notice, locale, retry_rx, self.run_chat_choices(&request, Some(stream))),
+                    Self::stream_into_messages(ctx, msg, locale, reply_channel, prompt, in_progress_message, progress_rx),
+                )
+            }).await;
+            self.cancellations.lock().remove(&msg.id);
+            let replied = response?.model;
+            // The streamed messages are already done, so the note follows them.
+            if let Some(note) = fallback_note(request.model.as_str(), replied.as_str(), locale) {
+                reply_channel.say(ctx, format!("*{note}*")).await?;
+            }
+            return Ok(());
+        }
+
+        let response = while_typing(ctx, reply_channel, with_retry_notices(ctx, notice, locale, retry_rx, self.run_chat_choices(&request, None))).await;
+
+        // Clean up before checking for errors, so that a timeout doesn't leave it hanging around.
+        if let Some(in_progress_message) = in_progress_message {
+            if in_progress_message.delete(ctx).await.ok().is_none() {
+                log::error!("Failed to delete in progress message. Continuing.");
+            }
+        }
+        let ChatReplies { mut choices, model: replied, .. } = response?;
+        let response = choices.swap_remove(0);
+        let note = fallback_note(request.model.as_str(), replied.as_str(), locale);
+
+        self.send_reply(ctx, msg, locale, reply_channel, format_reply_chunks(format, prompt, response.as_str(), replied.as_str(), note.as_deref())).await?;
+
+        Ok(())
+    }
+
+    /// Sends the first of `chunks` in `reply_channel` as a reply to `msg`. The rest are held back
+    /// behind a continue button.
+    async fn send_reply(&self, ctx: &Context, msg: &Message, locale: Locale, reply_channel: ChannelId, chunks: Vec<ReplyChunk>) -> Result<(), BotError> {
+        let (first, rest) = split_chunks(chunks);
+        let (first, more) = (&first, !rest.is_empty());
+        let message = with_discord_retries("send_reply", move || reply_channel.send_message(ctx, move |msg_builder| {
+            msg_builder
+                .content(first.content.as_str())
+                .add_embeds(first.embeds())
+                .allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse().replied_user(true))
+                .components(|components| add_chunk_buttons(components, more, locale));
+            // Replies can't reference a message in another channel, like a thread's parent.
+            if reply_channel == msg.channel_id {
+                msg_builder.reference_message(msg);
+            }
+            msg_builder
+        })).await?;
+        self.hold_continuation(message.id, rest);
+        Ok(())
+    }
+}
+
+/// Where [`with_retry_notices`] keeps the user posted.
+#[derive(Clone, Copy)]
+enum RetryNotice<'a> {
+    /// Nowhere.
+    None,
+    /// In a reply to `msg`, sent in `channel_id`.
+    Channel { channel_id: ChannelId, msg: &'a Message },
+    /// In place of the deferred response to the interaction with this token.
+    Interaction { token: &'a str },
+}
+
+/// Runs `chat`, which tells `retries` about each retry, while keeping the user posted on them
+/// through `notice`. The notice goes away once `chat` is done, however that went.
+async fn with_retry_notices<T>(ctx: &Context, notice: RetryNotice<'_>, locale: Locale, mut retries: watch::Receiver<u32>, chat: impl std::future::Future<Output = T>) -> T {
+    tokio::pin!(chat);
+    let mut shown: Option<MessageId> = None;
+    let result = loop {
+        tokio::select! {
+            result = &mut chat => break result,
+            Ok(()) = retries.changed() => {
+                let text = Text::StillWorking.get(locale).replace("{attempt}", retries.borrow_and_update().to_string().as_str());
+                let shown_now = match notice {
+                    RetryNotice::None => continue,
+                    RetryNotice::Channel { channel_id, msg } => match shown {
+                        Some(message_id) => channel_id.edit_message(ctx, message_id, |m| m.content(text.as_str())).await,
+                        None => channel_id.send_message(ctx, |m| {
+                            m.content(text.as_str()).allowed_mentions(|allowed_mentions| allowed_mentions.empty_parse());
+                            if channel_id == msg.channel_id {
+                                m.reference_message(msg);
+                            }
+                            m
+                        }).await,
+                    },
+                    RetryNotice::Interaction { token } => ctx.http.edit_original_interaction_response(token, &serde_json::json!({ "content": text })).await,
+                };
+                match shown_now {
+                    Ok(message) => shown = Some(message.id),
+                    Err(e) => log::warn!("Failed to show that the request is being retried. Continuing. Error: {e:?}"),
+                }
+            },
+        }
+    };
+
+    if let Some(message_id) = shown {
+        let removed = match notice {
+            RetryNotice::None => Ok(()),
+            RetryNotice::Channel { channel_id, .. } => channel_id.delete_message(ctx, message_id).await,
+            RetryNotice::Interaction { token } => ctx.http.delete_original_interaction_response(token).await,
+        };
+        if let Err(e) = removed {
+            log::warn!("Failed to remove the retry notice. Continuing. Error: {e:?}");
+        }
+    }
+    result
+}
+
+/// Put on a classic command while it's handled, when progress reactions are on.
+const PROCESSING_REACTION: char = '⏳';
+const SUCCESS_REACTION: char = '✅';
+const FAILURE_REACTION: char = '❌';
+
+/// Reacts to `msg` with `emoji`. Failing, say for lack of the Add Reactions permission, is only
+/// logged.
+async fn try_react(ctx: &Context, msg: &Message, emoji: char) -> Option<Reaction> {
+    match msg.react(ctx, emoji).await {
+        Ok(reaction) => Some(reaction),
+        Err(e) => {
+            log::warn!("Failed to react to {:?} with {emoji}. Do I have permission to add reactions? Continuing. Error: {e:?}", msg.id);
+            None
+        },
+    }
+}
+
+/// Most times registering slash commands is tried before giving up until the next `ready`.
+const COMMAND_REGISTRATION_ATTEMPTS: u32 = 3;
+
+/// Registers the slash commands in `guild_id`, or globally if there isn't one, retrying with backoff.
+/// Both replace whatever was registered before, so this is safe to repeat. Returns whether it worked.
+async fn register_commands(ctx: &Context, guild_id: Option<GuildId>) -> bool {
+    for attempt in 1..=COMMAND_REGISTRATION_ATTEMPTS {
+        let result = match guild_id {
+            Some(guild_id) => guild_id.set_application_commands(ctx, create_commands).await,
+            None => Command::set_global_application_commands(ctx, create_commands).await,
+        };
+        match result {
+            Ok(commands) => {
+                log::info!("Registered {} slash commands.", commands.len());
+                return true;
+            },
+            Err(e) if attempt < COMMAND_REGISTRATION_ATTEMPTS => {
+                let delay = backoff_delay(attempt);
+                log::warn!("Failed to register slash commands on attempt {attempt}. Retrying in {delay:?}. Error: {e:?}");
+                tokio::time::sleep(delay).await;
+            },
+            Err(e) => {
+                log::error!("Failed to register slash commands after {attempt} attempts. Giving up. Error: {e:?}");
+            },
+        }
+    }
+    false
+}
+
+fn create_commands(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command
+                .name("chat")
+                .description("Chat with an AI model.")
+                .create_option(|option| {
+                    option
+                        .name("prompt")
+                        .description("Prompt to pass onto the model")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("model")
+                        .description("name of the model to user. Defaults to the server's default model, if it has one.")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(true)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("temperature")
+                        .description("Sampling temperature, from 0 to 2. Higher is more random.")
+                        .kind(CommandOptionType::Number)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("top_p")
+                        .description("Nucleus sampling, from 0 to 1. Lower sticks to likelier words. Set this or temperature, not both.")
+                        .kind(CommandOptionType::Number)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("max_tokens")
+                        .description("Most tokens the reply may use. Defaults to 500.")
+                        .kind(CommandOptionType::Integer)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("presence_penalty")
+                        .description("From -2 to 2. Higher pushes the model towards new topics.")
+                        .kind(CommandOptionType::Number)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("frequency_penalty")
+                        .description("From -2 to 2. Higher makes the model repeat itself less.")
+                        .kind(CommandOptionType::Number)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("fallback")
+                        .description("If the model is still busy after retrying, answer with its fallback model. Defaults to false.")
+                        .kind(CommandOptionType::Boolean)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("json")
+                        .description("Make the reply a JSON object. The prompt or system prompt has to ask for JSON. Defaults to false.")
+                        .kind(CommandOptionType::Boolean)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("image")
+                        .description("An image for the model to look at. Only vision models can.")
+                        .kind(CommandOptionType::Attachment)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("choices")
+                        .description("How many replies to pick between, up to 4. Defaults to 1.")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .max_int_value(MAX_CHOICES)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("language")
+                        .description("Language to reply in, or `auto` for the prompt's language. Defaults to the bot's setting.")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("format")
+                        .description("How to lay out the reply. Defaults to the server's setting, or plain.")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false);
+                    for format in ReplyFormat::ALL {
+                        option.add_string_choice(format.as_str(), format.as_str());
+                    }
+                    option
+                })
+                .create_option(|option| {
+                    option
+                        .name("logprobs")
+                        .description("Show how likely the reply's first tokens were, with up to this many alternatives each, from 0 to 5")
+                        .kind(CommandOptionType::Integer)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("seed")
+                        .description("Any whole number. Asking again with the same seed and options tends to give the same reply.")
+                        .kind(CommandOptionType::Integer)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("stop")
+                        .description("Up to 4 sequences, separated by commas, where the model stops generating")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("system")
+                        .description("System prompt to steer the model. Replaces the current one for the rest of the chat.")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("private")
+                        .description("Only show the reply to you. Defaults to false.")
+                        .kind(CommandOptionType::Boolean)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("thread")
+                        .description("Start a thread to continue the conversation in. Defaults to false.")
+                        .kind(CommandOptionType::Boolean)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command.name("clear").description("Clear chat history")
+        })
+        .create_application_command(|command| {
+            command
+                .name("reset")
+                .description("Clear chat history everywhere, along with the settings that came with it")
+                .create_option(|option| {
+                    option
+                        .name("scope")
+                        .description("Whose settings to reset. Only server managers can reset the server. Defaults to me.")
+                        .kind(CommandOptionType::String)
+                        .add_string_choice("me", "me")
+                        .add_string_choice("server", "server")
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("config")
+                .description("Set this server's defaults. Options that aren't given are left as they are.")
+                .default_member_permissions(Permissions::MANAGE_GUILD)
+                .dm_permission(false)
+                .create_option(|option| {
+                    option
+                        .name("model")
+                        .description("Model to use when a chat doesn't name one")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(true)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("temperature")
+                        .description("Sampling temperature to use when a chat doesn't give one, from 0 to 2")
+                        .kind(CommandOptionType::Number)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("system")
+                        .description("System prompt to use when a chat doesn't have one")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("template")
+                        .description("Wraps every prompt, which goes where `{prompt}` is, like `Answer concisely. {prompt}`")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name("format")
+                        .description("How to lay out replies when a chat doesn't say")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(false);
+                    for format in ReplyFormat::ALL {
+                        option.add_string_choice(format.as_str(), format.as_str());
+                    }
+                    option
+                })
+                .create_option(|option| {
+                    option
+                        .name("reset")
+                        .description("Clear every default before applying the other options. Defaults to false.")
+                        .kind(CommandOptionType::Boolean)
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("image")
+                .description("Generate an image with DALL-E.")
+                .create_option(|option| {
+                    option
+                        .name("prompt")
+                        .description("Description of the image to generate")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(false)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name("size")
+                        .description("Size of the image. Defaults to 1024x1024.")
+                        .kind(CommandOptionType::String);
+                    for size in IMAGE_SIZES {
+                        option.add_string_choice(size, size);
+                    }
+                    option
+                        .set_autocomplete(false)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command.name("summarize").description("Replace your chat history here with a summary of it, to use fewer tokens")
+        })
+        .create_application_command(|command| {
+            command.name("export").description("Download your chat history here as a Markdown file")
+        })
+        .create_application_command(|command| {
+            command.name("context").description("Show what the bot remembers of your chat here, and about how many tokens it is")
+        })
+        .create_application_command(|command| {
+            command.name("pins").description("Send the pinned messages most relevant to your next prompt along with it")
+        })
+        .create_application_command(|command| {
+            command
+                .name("compose")
+                .description("Write a long prompt, with line breaks, in a text box")
+                .create_option(|option| {
+                    option
+                        .name("model")
+                        .description("name of the model to use. Defaults to the conversation's model, then the server's.")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(true)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("persona")
+                .description("Change how the bot behaves for the rest of this conversation, without clearing it")
+                .create_option(|option| {
+                    option
+                        .name("prompt")
+                        .description("The new system prompt, or `reset` to go back to the default")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name("setmodel")
+                .description("Use a model for the rest of this conversation, whenever a chat doesn't name one")
+                .create_option(|option| {
+                    option
+                        .name("model")
+                        .description("Model to switch to")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(true)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command.name("undo").description("Remove your last prompt and its reply from the chat history")
+        })
+        .create_application_command(|command| {
+            command.name("regenerate").description("Get a different answer to your last prompt")
+        })
+        .create_application_command(|command| {
+            command.name("stop_generation").description("Refuse every new request and stop the replies in progress. Bot admins only.")
+        })
+        .create_application_command(|command| {
+            command.name("resume").description("Answer requests again after `stop_generation`. Bot admins only.")
+        })
+        .create_application_command(|command| {
+            command.name("ping").description("Check whether the bot and OpenAI are responding")
+        })
+        .create_application_command(|command| {
+            command.name("help").description("Describe every command")
+        })
+        .create_application_command(|command| {
+            command.name("models").description("List the chat models OpenAI currently offers")
+        })
+        .create_application_command(|command| {
+            command.name("usage").description("Show your token usage and estimated cost. Not reset by clearing history.")
+        })
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, data_about_bot: Ready) {
+        let content_flags = ApplicationFlags::GATEWAY_MESSAGE_CONTENT | ApplicationFlags::GATEWAY_MESSAGE_CONTENT_LIMITED;
+        if self.cfg.classic_commands && !data_about_bot.application.flags.intersects(content_flags) {
+            log::warn!("The Message Content intent isn't enabled for this application, so classic commands won't work outside DMs and mentions. Enable it under Bot > Privileged Gateway Intents in the Discord developer portal, or set `CLASSIC_COMMANDS=false` to turn classic commands off.");
+        }
+
+        if self.commands_registered.load(Ordering::Relaxed) {
+            log::info!("Slash commands were already registered. Skipping.");
+            return;
+        }
+
+        let registered = if self.cfg.dev_guilds.is_empty() {
+            log::info!("Setting up global commands.");
+            register_commands(&ctx, None).await
+        } else {
+            let mut registered = true;
+            for &guild_id in &self.cfg.dev_guilds {
+                log::info!("Setting up commands in dev guild {guild_id:?}.");
+                registered &= register_commands(&ctx, Some(guild_id)).await;
+            }
+            registered
+        };
+        self.commands_registered.store(registered, Ordering::Relaxed);
     }
 
     async fn message(
@@ -379,14 +6213,19 @@ impl EventHandler for Handler {
         ctx: Context,
         new_message: Message,
     ) {
-        let ui = "discord_classic";
-        let message_id = new_message.id;
-        let start = chrono::Utc::now();
+        if !self.cfg.classic_commands {
+            return;
+        }
+        async {
+            let ui = "discord_classic";
+            let message_id = new_message.id;
+            let start = chrono::Utc::now();
 
-        self.handle_message_and_errors(ctx, new_message).await;
+            self.handle_message_and_errors(ctx, new_message).await;
 
-        let end = chrono::Utc::now();
-        Self::show_time(ui, "message", message_id, start, end);
+            let end = chrono::Utc::now();
+            self.show_time(ui, "message", message_id, start, end);
+        }.instrument(self.request_span()).await
     }
 
     async fn interaction_create(
@@ -394,43 +6233,838 @@ impl EventHandler for Handler {
         ctx: Context,
         interaction: Interaction,
     ) {
-        let start = chrono::Utc::now();
+        async {
+            let start = chrono::Utc::now();
 
-        let (ui, interaction_id) = match interaction {
-            Interaction::Ping(_) => {
-                ("discord_ping", 0.into())
-            }
-            Interaction::ModalSubmit(submission) => {
-                ("discord_modalsub", submission.id)
-            }
-            Interaction::Autocomplete(autocomplete) => {
-                let id = autocomplete.id;
-                self.handle_autocomp_and_errors(ctx, autocomplete).await;
-                ("discord_autocomp", id)
-            }
-            Interaction::MessageComponent(component) => {
-                let id = component.id;
-                self.handle_msgcomp_and_errors(ctx, component).await;
-                ("discord_msgcomp", id)
-            }
-            Interaction::ApplicationCommand(command) => {
-                let id = command.id;
-                self.handle_appcomm_and_errors(ctx, command).await;
-                ("discord_appcomm", id)
-            }
-        };
-        let end = chrono::Utc::now();
-        Self::show_time(ui, "interaction", interaction_id, start, end);
+            let (ui, interaction_id) = match interaction {
+                Interaction::Ping(_) => {
+                    ("discord_ping", 0.into())
+                }
+                Interaction::ModalSubmit(submission) => {
+                    let id = submission.id;
+                    self.handle_modal_and_errors(ctx, submission).await;
+                    ("discord_modalsub", id)
+                }
+                Interaction::Autocomplete(autocomplete) => {
+                    let id = autocomplete.id;
+                    self.handle_autocomp_and_errors(ctx, autocomplete).await;
+                    ("discord_autocomp", id)
+                }
+                Interaction::MessageComponent(component) => {
+                    let id = component.id;
+                    self.handle_msgcomp_and_errors(ctx, component).await;
+                    ("discord_msgcomp", id)
+                }
+                Interaction::ApplicationCommand(command) => {
+                    let id = command.id;
+                    self.handle_appcomm_and_errors(ctx, command).await;
+                    ("discord_appcomm", id)
+                }
+            };
+            let end = chrono::Utc::now();
+            self.show_time(ui, "interaction", interaction_id, start, end);
+        }.instrument(self.request_span()).await
     }
 }
 
 #[tokio::main]
 async fn main() {
-    setup_logging(LoggingCfg {
-        level: "info".to_owned(),
-        filter: None,
+    let cfg = Arc::new(Config::from_env());
+
+    setup_logging(LoggingCfg::from_env());
+
+    let metrics = cfg.metrics_port.map(|port| {
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        tokio::spawn(serve_metrics(port, Arc::clone(&metrics)));
+        metrics
+    });
+
+    let recent_requests = cfg.dashboard_port.map(|port| {
+        let token = cfg.dashboard_token.clone().expect("environment variable `DASHBOARD_TOKEN` is required when `DASHBOARD_PORT` is set");
+        let recent_requests = Arc::new(RecentRequests::new(cfg.dashboard_requests));
+        tokio::spawn(serve_dashboard(port, token, Arc::clone(&recent_requests)));
+        recent_requests
     });
 
-    let mut client = build_client().await.expect("no error");
-    client.start().await.expect("no error");
+    let mut client = build_client(cfg.discord_token.as_str(), Arc::clone(&cfg), metrics, recent_requests).await.expect("no error");
+    client.data.write().await.insert::<ShardManagerContainer>(Arc::clone(&client.shard_manager));
+    if let Err(e) = client.start().await {
+        if let serenity::Error::Gateway(serenity::gateway::GatewayError::DisallowedGatewayIntents) = e {
+            panic!("Discord refused the Message Content intent. Enable it under Bot > Privileged Gateway Intents in the Discord developer portal, or set `CLASSIC_COMMANDS=false` to run with slash commands only.");
+        }
+        panic!("The Discord client stopped. Error: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for OpenAI, answering with the scripted responses in order and keeping the body of
+    /// every request.
+    struct MockOpenAi {
+        base_url: String,
+        requests: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    impl MockOpenAi {
+        fn start(responses: Vec<(u16, serde_json::Value)>) -> Self {
+            Self::start_raw(responses.into_iter().map(|(status, body)| (status, body.to_string())).collect())
+        }
+
+        /// Like [`MockOpenAi::start`], but with bodies sent as is, so that they needn't be JSON.
+        fn start_raw(responses: Vec<(u16, String)>) -> Self {
+            let responses = Arc::new(Mutex::new(responses));
+            let requests = Arc::new(Mutex::new(vec![]));
+            let make_service = {
+                let requests = Arc::clone(&requests);
+                hyper::service::make_service_fn(move |_| {
+                    let (responses, requests) = (Arc::clone(&responses), Arc::clone(&requests));
+                    async move {
+                        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                            let (responses, requests) = (Arc::clone(&responses), Arc::clone(&requests));
+                            async move {
+                                let body = hyper::body::to_bytes(request.into_body()).await?;
+                                requests.lock().push(serde_json::from_slice(&body).expect("request body to be JSON"));
+                                let (status, body) = {
+                                    let mut responses = responses.lock();
+                                    assert!(!responses.is_empty(), "more requests than scripted responses");
+                                    responses.remove(0)
+                                };
+                                let response = hyper::Response::builder()
+                                    .status(status)
+                                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                                    // So that retries don't wait.
+                                    .header(hyper::header::RETRY_AFTER, "0")
+                                    .body(hyper::Body::from(body))
+                                    .expect("response to be valid");
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        }))
+                    }
+                })
+            };
+            let server = hyper::Server::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+            let base_url = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+            Self { base_url, requests }
+        }
+
+        fn requests(&self) -> Vec<serde_json::Value> {
+            self.requests.lock().clone()
+        }
+    }
+
+    /// The defaults [`Config::from_env`] would pick, with OpenAI at `openai_base_url`.
+    fn config(openai_base_url: &str) -> Config {
+        Config {
+            openai_api_keys: vec!["test-key".to_owned()],
+            openai_org_id: None,
+            openai_base_url: openai_base_url.to_owned(),
+            openai_azure: false,
+            openai_api_version: None,
+            hash_user_ids: true,
+
+            discord_secret: None,
+            discord_token: "test-token".to_owned(),
+
+            history_token_budget: 2000,
+            max_history_turns: 100,
+            history_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            reply_chain_depth: 10,
+            default_model: "gpt-3.5-turbo".to_owned(),
+            default_temperature: 1.0,
+            default_system_prompt: None,
+            default_reply_language: None,
+            default_stop: vec![],
+
+            retry_max_attempts: 5,
+            retry_max_elapsed: Duration::from_secs(60),
+            circuit_failure_threshold: 5,
+            circuit_window: Duration::from_secs(60),
+            circuit_cooldown: Duration::from_secs(30),
+            max_concurrent_requests: 4,
+            request_queue_timeout: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(120),
+            connect_timeout: Duration::from_secs(10),
+            model_list_ttl: Duration::from_secs(3600),
+            fallback_models: HashMap::new(),
+
+            command_prefix: "-".to_owned(),
+            max_prompt_chars: 4000,
+            stream_responses: false,
+            classic_commands: true,
+            progress_reactions: false,
+            moderate_prompts: false,
+            response_cache_size: 0,
+            response_cache_ttl: Duration::from_secs(3600),
+            dry_run: false,
+            max_audio_bytes: 25 * 1024 * 1024,
+            max_tool_rounds: 5,
+
+            allowed_users: HashSet::new(),
+            allowed_roles: HashSet::new(),
+            allowed_channels: HashSet::new(),
+            dashboard_port: None,
+            dashboard_token: None,
+            dashboard_requests: 50,
+            metrics_port: None,
+            dev_guilds: vec![],
+
+            admin_users: HashSet::new(),
+            cooldown: Duration::ZERO,
+            monthly_budget: 0.0,
+            monthly_user_budget: 0.0,
+            spend_file: "spend.json".to_owned(),
+            // Never written, since no test configures a guild.
+            guild_config_file: std::env::temp_dir().join("chatgpt-test-guild-configs.json").to_string_lossy().into_owned(),
+        }
+    }
+
+    fn handler(openai: &MockOpenAi) -> Handler {
+        Handler::new(Arc::new(config(openai.base_url.as_str())), None, None)
+    }
+
+    const KEY: HistoryKey = HistoryKey { guild_id: None, channel_id: ChannelId(1), thread_id: None, user_id: UserId(2) };
+
+    fn chat_request<'a>(handler: &Handler, prompt: &'a str) -> ChatRequest<'a> {
+        let (model, options) = handler.resolve_options(KEY, RequestedOptions::default()).expect("default options to be valid");
+        ChatRequest {
+            key: KEY,
+            user_name: "tester",
+            model,
+            prompt,
+            images: vec![],
+            options,
+            reply_chain: None,
+            retry_notices: None,
+            fallback: false,
+        }
+    }
+
+    fn history_turns(handler: &Handler) -> usize {
+        handler.chat_histories.lock().get(&KEY).map_or(0, |history| history.lock().turns.len())
+    }
+
+    fn chat_reply(content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 3, "total_tokens": 13 },
+        })
+    }
+
+    #[tokio::test]
+    async fn chat_sends_prompt_and_parses_reply() {
+        let openai = MockOpenAi::start(vec![(200, chat_reply("Hi there!"))]);
+        let handler = handler(&openai);
+        let request = chat_request(&handler, "Hello");
+
+        let reply = handler.run_chat(&request, None).await.expect("chat to succeed");
+
+        assert_eq!(reply, "Hi there!");
+        let requests = openai.requests();
+        assert_eq!(requests.len(), 1);
+        let body = &requests[0];
+        assert_eq!(body["model"], request.model.as_str());
+        assert_eq!(body["user"], handler.openai_user(KEY.user_id).as_str());
+        let messages = body["messages"].as_array().expect("messages to be a list");
+        assert_eq!(messages.last(), Some(&serde_json::json!({ "role": "user", "content": "Hello" })));
+        assert_eq!(history_turns(&handler), 2);
+    }
+
+    #[tokio::test]
+    async fn chat_retries_when_rate_limited() {
+        let rate_limited = serde_json::json!({ "error": { "message": "Rate limit reached", "type": "requests", "code": null } });
+        let openai = MockOpenAi::start(vec![(429, rate_limited), (200, chat_reply("Made it."))]);
+        let handler = handler(&openai);
+        let request = chat_request(&handler, "Hello");
+
+        let reply = handler.run_chat(&request, None).await.expect("chat to succeed after a retry");
+
+        assert_eq!(reply, "Made it.");
+        let requests = openai.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0], requests[1]);
+    }
+
+    #[tokio::test]
+    async fn chat_reports_error_body() {
+        let error = serde_json::json!({ "error": { "message": "Something was wrong with that.", "type": "invalid_request_error", "code": null } });
+        let openai = MockOpenAi::start(vec![(400, error)]);
+        let handler = handler(&openai);
+        let mut request = chat_request(&handler, "Hello");
+        request.options.system_prompt = Some("You are a pirate.".to_owned());
+
+        let result = handler.run_chat(&request, None).await;
+
+        assert!(matches!(result, Err(BotError::OpenAi(message)) if message == "OpenAI returned an error: Something was wrong with that."));
+        assert_eq!(openai.requests().len(), 1);
+        assert_eq!(history_turns(&handler), 0);
+        let system_prompt = handler.chat_histories.lock().get(&KEY).and_then(|history| history.lock().system_prompt.clone());
+        assert_eq!(system_prompt, None);
+    }
+
+    /// Checks that every chunk fits in `max_chars` and that, put back together with `separator` in
+    /// place of the characters broken on, they make up `content`.
+    fn assert_chunks(chunks: &[String], content: &str, max_chars: usize, separator: &str) {
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= max_chars), "a chunk is over {max_chars} characters");
+        assert_eq!(chunks.join(separator), content);
+    }
+
+    #[test]
+    fn chunk_message_splits_at_whitespace() {
+        let content = ["word"; 1000].join(" ");
+        assert_eq!(content.chars().count(), 4999);
+
+        let chunks = chunk_message(content.as_str());
+
+        assert_eq!(chunks.len(), 3);
+        assert_chunks(chunks.as_slice(), content.as_str(), DISCORD_MESSAGE_LIMIT, " ");
+    }
+
+    #[test]
+    fn chunk_message_prefers_newlines() {
+        let line = "a".repeat(100);
+        let content = format!("{}\n{}", [line.as_str(); 15].join(" "), "b".repeat(1000));
+
+        let chunks = chunk_message(content.as_str());
+
+        assert_eq!(chunks.len(), 2);
+        assert_chunks(chunks.as_slice(), content.as_str(), DISCORD_MESSAGE_LIMIT, "\n");
+    }
+
+    #[test]
+    fn chunk_message_splits_without_whitespace() {
+        let content = "x".repeat(5000);
+
+        let chunks = chunk_message(content.as_str());
+
+        // Room is kept for the fence that would close a split code block.
+        assert_eq!(chunks.iter().map(|chunk| chunk.chars().count()).collect::<Vec<_>>(), [1996, 1996, 1008]);
+        assert_chunks(chunks.as_slice(), content.as_str(), DISCORD_MESSAGE_LIMIT, "");
+    }
+
+    #[test]
+    fn chunk_message_keeps_multibyte_chars_whole() {
+        // Splitting by bytes rather than characters would land inside an emoji for each of these.
+        let full_chunk = DISCORD_MESSAGE_LIMIT - CODE_FENCE.len() - 1;
+        for leading in full_chunk - 4..full_chunk {
+            let content = format!("{}{}", "a".repeat(leading), "😀".repeat(10));
+
+            let chunks = chunk_message(content.as_str());
+
+            assert_eq!(chunks.len(), 2);
+            assert_eq!(chunks[0].chars().count(), full_chunk);
+            assert_chunks(chunks.as_slice(), content.as_str(), DISCORD_MESSAGE_LIMIT, "");
+        }
+        let content = "🦀".repeat(5000);
+        assert_chunks(chunk_message(content.as_str()).as_slice(), content.as_str(), DISCORD_MESSAGE_LIMIT, "");
+    }
+
+
+    #[test]
+    fn format_duration_picks_units() {
+        let cases = [
+            (chrono::Duration::zero(), "0ms"),
+            (chrono::Duration::milliseconds(750), "750ms"),
+            (chrono::Duration::milliseconds(999), "999ms"),
+            (chrono::Duration::seconds(1), "1.0s"),
+            (chrono::Duration::milliseconds(1500), "1.5s"),
+            (chrono::Duration::milliseconds(59_900), "59.9s"),
+            (chrono::Duration::minutes(1), "1m 0s"),
+            (chrono::Duration::seconds(123), "2m 3s"),
+            (chrono::Duration::seconds(59 * 60 + 59), "59m 59s"),
+            (chrono::Duration::seconds(60 * 60 + 5), "1h 0m 5s"),
+        ];
+        for (duration, expected) in cases {
+            assert_eq!(format_duration(duration), expected, "formatting {duration:?}");
+        }
+    }
+
+
+    #[test]
+    fn cooldown_remaining_until_cooldown_ends() {
+        let cooldown = Duration::from_secs(10);
+        let last_request = Instant::now();
+
+        assert_eq!(cooldown_remaining(last_request, last_request, cooldown), Some(cooldown));
+        assert_eq!(cooldown_remaining(last_request, last_request + Duration::from_secs(4), cooldown), Some(Duration::from_secs(6)));
+        assert_eq!(cooldown_remaining(last_request, last_request + Duration::from_millis(9_999), cooldown), Some(Duration::from_millis(1)));
+        assert_eq!(cooldown_remaining(last_request, last_request + cooldown, cooldown), None);
+        assert_eq!(cooldown_remaining(last_request, last_request + Duration::from_secs(60), cooldown), None);
+    }
+
+    #[test]
+    fn cooldown_remaining_with_clock_before_last_request() {
+        let now = Instant::now();
+
+        assert_eq!(cooldown_remaining(now + Duration::from_secs(1), now, Duration::from_secs(10)), Some(Duration::from_secs(10)));
+    }
+
+
+    /// Options with every sampling option set.
+    fn sampling_options() -> CompletionOptions {
+        CompletionOptions {
+            temperature: 0.7,
+            max_tokens: 300,
+            system_prompt: None,
+            stop: vec![],
+            top_p: Some(0.9),
+            presence_penalty: Some(0.5),
+            frequency_penalty: Some(-0.5),
+            n: 1,
+            seed: None,
+            logprobs: None,
+            reply_language: None,
+            json: false,
+        }
+    }
+
+    #[test]
+    fn build_chat_completion_keeps_sampling_for_classic_models() {
+        let body = build_chat_completion("gpt-4", None, &[], "Hello", &[], &sampling_options());
+
+        assert_eq!(body["temperature"], 0.7);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body["presence_penalty"], 0.5);
+        assert_eq!(body["frequency_penalty"], -0.5);
+        assert_eq!(body["max_tokens"], 300);
+        assert!(body.get("max_completion_tokens").is_none());
+    }
+
+    #[test]
+    fn build_chat_completion_drops_sampling_for_reasoning_models() {
+        let body = build_chat_completion("o1-mini", None, &[], "Hello", &[], &sampling_options());
+
+        for param in ["temperature", "top_p", "presence_penalty", "frequency_penalty", "max_tokens"] {
+            assert!(body.get(param).is_none(), "`{param}` was sent to o1-mini");
+        }
+        assert_eq!(body["max_completion_tokens"], 300);
+    }
+
+
+    #[test]
+    fn validate_json_reply_accepts_only_json() {
+        assert!(validate_json_reply(r#"{"names": ["Ada", "Grace"]}"#).is_ok());
+        assert!(validate_json_reply("[1, 2, 3]").is_ok());
+
+        for reply in ["", "Sure! Here's the JSON:", r#"{"names": ["Ada", "Gr"#] {
+            assert!(matches!(validate_json_reply(reply), Err(BotError::OpenAi(_))), "accepted {reply:?}");
+        }
+    }
+
+    #[test]
+    fn validate_json_mention_needs_json_asked_for() {
+        assert!(validate_json_mention(None, "List three names as JSON").is_ok());
+        assert!(validate_json_mention(Some("Always reply in json."), "List three names").is_ok());
+
+        assert!(matches!(validate_json_mention(None, "List three names"), Err(BotError::UserInput(_))));
+        assert!(matches!(validate_json_mention(Some("Be brief."), "List three names"), Err(BotError::UserInput(_))));
+    }
+
+    #[test]
+    fn format_reply_quotes_prompt_apart_from_response() {
+        let reply = format_reply("What's 2 + 2?\nShow your work.", "\n\n4, since 2 + 2 = 4.");
+
+        assert_eq!(reply, "> What's 2 + 2?\n> Show your work.\n4, since 2 + 2 = 4.");
+        assert!(!reply.starts_with("What's 2 + 2?"));
+
+        let chunks = format_reply_chunks(ReplyFormat::Plain, "Hello", " Hi there!", "gpt-4", None);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].content, "> Hello\nHi there!");
+        assert!(!chunks[0].content.contains("Hello Hi there!"));
+    }
+
+    #[tokio::test]
+    async fn chat_reports_reply_without_choices() {
+        let reply = serde_json::json!({ "choices": [], "usage": { "prompt_tokens": 10, "completion_tokens": 0, "total_tokens": 10 } });
+        let openai = MockOpenAi::start(vec![(200, reply)]);
+        let handler = handler(&openai);
+        let request = chat_request(&handler, "Hello");
+
+        let result = handler.run_chat(&request, None).await;
+
+        assert!(matches!(result, Err(BotError::OpenAi(message)) if message == "OpenAI sent back an empty response."));
+        assert_eq!(history_turns(&handler), 0);
+    }
+
+    #[tokio::test]
+    async fn chat_reports_reply_that_is_not_json() {
+        let openai = MockOpenAi::start_raw(vec![(200, "<html>Bad gateway</html>".to_owned())]);
+        let handler = handler(&openai);
+        let request = chat_request(&handler, "Hello");
+
+        let result = handler.run_chat(&request, None).await;
+
+        assert!(matches!(result, Err(BotError::OpenAi(message)) if message == "OpenAI sent back a response that couldn't be read."));
+        assert_eq!(history_turns(&handler), 0);
+    }
+
+    #[tokio::test]
+    async fn resolve_options_checks_temperature_range() {
+        let openai = MockOpenAi::start(vec![]);
+        let handler = handler(&openai);
+        let cases = [(0.0, true), (0.7, true), (2.0, true), (-0.1, false), (2.01, false), (f64::NAN, false)];
+        for (temperature, valid) in cases {
+            let requested = RequestedOptions { temperature: Some(temperature), ..RequestedOptions::default() };
+
+            match handler.resolve_options(KEY, requested) {
+                Ok((_, options)) => assert!(valid && options.temperature == temperature, "accepted temperature {temperature}"),
+                Err(e) => assert!(!valid && matches!(e, BotError::UserInput(_)), "rejected temperature {temperature} with {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_options_checks_penalty_range() {
+        let openai = MockOpenAi::start(vec![]);
+        let handler = handler(&openai);
+        let cases = [(-2.0, true), (0.0, true), (2.0, true), (-2.01, false), (2.5, false)];
+        for (penalty, valid) in cases {
+            let requested = RequestedOptions { presence_penalty: Some(penalty), ..RequestedOptions::default() };
+            let result = handler.resolve_options(KEY, requested);
+            assert_eq!(result.as_ref().ok().and_then(|(_, options)| options.presence_penalty), valid.then_some(penalty), "presence penalty {penalty}");
+            assert!(valid || matches!(result, Err(BotError::UserInput(message)) if message.starts_with("Presence penalty")));
+
+            let requested = RequestedOptions { frequency_penalty: Some(penalty), ..RequestedOptions::default() };
+            let result = handler.resolve_options(KEY, requested);
+            assert_eq!(result.as_ref().ok().and_then(|(_, options)| options.frequency_penalty), valid.then_some(penalty), "frequency penalty {penalty}");
+            assert!(valid || matches!(result, Err(BotError::UserInput(message)) if message.starts_with("Frequency penalty")));
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_options_checks_top_p_range() {
+        let openai = MockOpenAi::start(vec![]);
+        let handler = handler(&openai);
+        let cases = [(0.0, true), (0.5, true), (1.0, true), (-0.01, false), (1.01, false)];
+        for (top_p, valid) in cases {
+            let requested = RequestedOptions { top_p: Some(top_p), ..RequestedOptions::default() };
+            let result = handler.resolve_options(KEY, requested);
+
+            assert_eq!(result.as_ref().ok().and_then(|(_, options)| options.top_p), valid.then_some(top_p), "top p {top_p}");
+            assert!(valid || matches!(result, Err(BotError::UserInput(_))), "top p {top_p}");
+        }
+    }
+
+    #[test]
+    fn sampling_warning_only_for_temperature_with_top_p() {
+        let cases = [(None, None, false), (Some(0.7), None, false), (None, Some(0.9), false), (Some(0.7), Some(0.9), true), (Some(0.0), Some(1.0), true)];
+        for (temperature, top_p, warned) in cases {
+            let requested = RequestedOptions { temperature, top_p, ..RequestedOptions::default() };
+            assert_eq!(sampling_warning(&requested).is_some(), warned, "temperature {temperature:?} and top p {top_p:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_options_passes_any_seed_through() {
+        let openai = MockOpenAi::start(vec![]);
+        let handler = handler(&openai);
+        for seed in [i64::MIN, -1, 0, 1, i64::MAX] {
+            let requested = RequestedOptions { seed: Some(seed), ..RequestedOptions::default() };
+            let (model, options) = handler.resolve_options(KEY, requested).expect("any seed to be accepted");
+
+            assert_eq!(options.seed, Some(seed));
+            assert_eq!(build_chat_completion(model.as_str(), None, &[], "Hello", &[], &options)["seed"], seed);
+        }
+
+        let (model, options) = handler.resolve_options(KEY, RequestedOptions::default()).expect("options to resolve");
+        assert!(build_chat_completion(model.as_str(), None, &[], "Hello", &[], &options).get("seed").is_none());
+    }
+
+    #[test]
+    fn validate_stop_allows_up_to_four_sequences() {
+        let cases = [("", Some(0)), ("END", Some(1)), ("a,,b", Some(2)), ("a,b,c,d", Some(4)), ("a,b,c,d,e", None)];
+        for (value, expected) in cases {
+            let result = validate_stop(parse_stop(value));
+            assert_eq!(result.as_ref().ok().map(Vec::len), expected, "stop {value:?}");
+            assert!(expected.is_some() || matches!(result, Err(BotError::UserInput(_))), "stop {value:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_keeps_whitespace_reply_out_of_history() {
+        let openai = MockOpenAi::start(vec![(200, chat_reply(" \n\t "))]);
+        let handler = handler(&openai);
+        let request = chat_request(&handler, "Hello");
+
+        let reply = handler.run_chat(&request, None).await.expect("chat to succeed");
+
+        assert_eq!(reply, EMPTY_RESPONSE_PLACEHOLDER);
+        assert_eq!(history_turns(&handler), 0);
+    }
+
+    #[tokio::test]
+    async fn chat_refuses_flagged_prompt() {
+        let flagged = serde_json::json!({ "id": "modr-1", "model": "text-moderation-007", "results": [{ "flagged": true, "categories": { "harassment": true, "violence": false } }] });
+        let openai = MockOpenAi::start(vec![(200, flagged)]);
+        let handler = Handler::new(Arc::new(Config { moderate_prompts: true, ..config(openai.base_url.as_str()) }), None, None);
+        let request = chat_request(&handler, "Something nasty");
+
+        let result = handler.run_chat(&request, None).await;
+
+        assert!(matches!(result, Err(BotError::UserInput(message)) if message == "Sorry, that prompt was flagged by moderation for: harassment."));
+        assert_eq!(openai.requests(), vec![serde_json::json!({ "input": "Something nasty" })]);
+        assert_eq!(history_turns(&handler), 0);
+    }
+
+    #[test]
+    fn split_classic_command_uses_prefix() {
+        let cases = [
+            ("-chat hi there", "-", Some(("chat", "hi there"))),
+            ("!chat hi there", "!", Some(("chat", "hi there"))),
+            ("gpt: chat hi", "gpt: ", Some(("chat", "hi"))),
+            ("!!clear", "!!", Some(("clear", ""))),
+            ("-chat hi there", "!", None),
+            ("chat hi", "!", None),
+        ];
+        for (content, prefix, expected) in cases {
+            assert_eq!(split_classic_command(content, prefix), expected, "{content:?} with prefix {prefix:?}");
+        }
+    }
+
+    #[test]
+    fn split_classic_model_only_takes_known_models() {
+        assert_eq!(split_classic_model("gpt-4 hi there"), (Some("gpt-4"), "hi there"));
+        assert_eq!(split_classic_model("gpt-4"), (Some("gpt-4"), ""));
+        assert_eq!(split_classic_model("hi there"), (None, "hi there"));
+        assert_eq!(split_classic_model("gpt-9000 hi"), (None, "gpt-9000 hi"));
+        assert_eq!(split_classic_model(""), (None, ""));
+    }
+
+    #[test]
+    fn split_classic_option_takes_leading_options() {
+        let (_, args) = split_classic_command("-chat gpt-4 max_tokens=50 top_p=0.5 hi there", "-").expect("a command");
+        let (model, rest) = split_classic_model(args);
+        assert_eq!(model, Some("gpt-4"));
+
+        assert_eq!(split_classic_option(rest), Some(("max_tokens", "50", "top_p=0.5 hi there")));
+        assert_eq!(split_classic_option("top_p=0.5 hi there"), Some(("top_p", "0.5", "hi there")));
+        assert_eq!(split_classic_option("hi there"), None);
+        assert_eq!(split_classic_option("thread=true"), Some(("thread", "true", "")));
+        // Names are checked by the caller, which leaves unknown ones in the prompt.
+        assert_eq!(split_classic_option("x=1 + 1"), Some(("x", "1", "+ 1")));
+    }
+
+    #[test]
+    fn chunk_message_reopens_split_code_block() {
+        let code = (0..300).map(|i| format!("let x{i} = {i};")).collect::<Vec<_>>().join("\n");
+        let content = format!("Here you go:\n```rust\n{code}\n```\nThat's all.");
+
+        let chunks = chunk_message(content.as_str());
+
+        assert!(chunks.len() > 1);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.chars().count() <= DISCORD_MESSAGE_LIMIT);
+            assert_eq!(unclosed_code_fence(chunk), None, "chunk {i} leaves a code block open");
+            if i > 0 {
+                assert!(chunk.starts_with("```rust\n"), "chunk {i} doesn't reopen the code block");
+            }
+        }
+        assert!(chunks.last().is_some_and(|chunk| chunk.ends_with("```\nThat's all.")));
+    }
+
+    #[test]
+    fn chunk_message_closes_unbalanced_code_block() {
+        let chunks = chunk_message("Try this:\n```py\nprint('hi')");
+
+        assert_eq!(chunks, vec!["Try this:\n```py\nprint('hi')\n```".to_owned()]);
+    }
+
+    #[test]
+    fn code_reply_escapes_fences_in_response() {
+        let response = "Run:\n```sh\ncargo test\n```";
+
+        let chunks = format_reply_chunks(ReplyFormat::Code, "How do I test?", response, "gpt-4", None);
+
+        assert_eq!(chunks.len(), 1);
+        let content = chunks[0].content.as_str();
+        assert_eq!(content.matches(CODE_FENCE).count(), 2, "the response's fences weren't broken up: {content:?}");
+        assert!(content.starts_with("> How do I test?\n```\n") && content.ends_with("\n```"));
+        assert!(content.contains("cargo test"));
+    }
+
+    #[test]
+    fn openai_error_advises_on_known_codes() {
+        let error = |code: Option<&str>| OpenAiError { message: "Raw message.".to_owned(), kind: None, code: code.map(str::to_owned) };
+
+        assert_eq!(error(Some("insufficient_quota")).user_message(), "The bot has run out of OpenAI credits. Let whoever runs it know.");
+        assert_eq!(error(Some("context_length_exceeded")).user_message(), "That conversation is too long for the model. Try clearing your chat history, or asking for fewer tokens.");
+        assert_eq!(error(Some("something_new")).user_message(), "OpenAI returned an error: Raw message.");
+        assert_eq!(error(None).user_message(), "OpenAI returned an error: Raw message.");
+    }
+
+    #[tokio::test]
+    async fn chat_keeps_dm_and_guild_histories_apart() {
+        let openai = MockOpenAi::start(vec![(200, chat_reply("Hi in DMs.")), (200, chat_reply("Hi in the server."))]);
+        let handler = handler(&openai);
+        let guild_key = HistoryKey { guild_id: Some(GuildId(3)), ..KEY };
+
+        handler.run_chat(&chat_request(&handler, "Hello from DMs"), None).await.expect("chat to succeed");
+        let request = ChatRequest { key: guild_key, ..chat_request(&handler, "Hello from the server") };
+        handler.run_chat(&request, None).await.expect("chat to succeed");
+
+        let contents = |key: HistoryKey| -> Vec<String> {
+            let history = Arc::clone(&handler.chat_histories.lock()[&key]);
+            let contents = history.lock().turns.iter().map(|turn| turn.content.clone()).collect();
+            contents
+        };
+        assert_eq!(contents(KEY), ["Hello from DMs", "Hi in DMs."]);
+        assert_eq!(contents(guild_key), ["Hello from the server", "Hi in the server."]);
+        let messages = openai.requests()[1]["messages"].to_string();
+        assert!(!messages.contains("DMs"), "the DM history was sent with the server's request");
+    }
+
+    #[test]
+    fn cap_turns_forgets_oldest_turns() {
+        let turn = |content: &str| ChatTurn { role: Role::User, name: "tester".to_owned(), content: content.to_owned(), at: chrono::Utc::now() };
+        let contents = |turns: &[ChatTurn]| turns.iter().map(|turn| turn.content.clone()).collect::<Vec<_>>();
+
+        let mut turns: Vec<_> = ["1", "2", "3", "4", "5"].into_iter().map(turn).collect();
+        assert_eq!(cap_turns(&mut turns, 5), 0);
+        assert_eq!(contents(&turns), ["1", "2", "3", "4", "5"]);
+        assert_eq!(cap_turns(&mut turns, 3), 2);
+        assert_eq!(contents(&turns), ["3", "4", "5"]);
+        assert_eq!(cap_turns(&mut turns, 0), 3);
+        assert!(turns.is_empty());
+        assert_eq!(cap_turns(&mut turns, 2), 0);
+    }
+
+    #[tokio::test]
+    async fn read_completion_stream_collects_text_and_usage() {
+        let events = [
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":""}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"Hi"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" there!"},"finish_reason":"stop"}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":10,"completion_tokens":3,"total_tokens":13}}"#,
+            "[DONE]",
+        ];
+        let body = events.map(|event| format!("data: {event}\n\n")).concat();
+        let openai = MockOpenAi::start_raw(vec![(200, body)]);
+        let response = reqwest::Client::new().post(openai.base_url.as_str()).json(&serde_json::json!({})).send().await.expect("the mock to answer");
+        let (progress, progress_rx) = watch::channel(String::new());
+        let target = StreamTarget { progress, cancel: CancellationToken::new() };
+
+        let (text, usage) = read_completion_stream(response, &target).await.expect("the stream to be read");
+
+        assert_eq!(text, "Hi there!");
+        assert_eq!(*progress_rx.borrow(), "Hi there!");
+        let usage = usage.expect("usage to be read from the last chunk");
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (10, 3, 13));
+    }
+
+    #[test]
+    fn is_from_bot_ignores_bots_and_ourselves() {
+        let bot_id = UserId(10);
+        let user = |id: u64, bot: bool| {
+            let mut user = User::default();
+            user.id = UserId(id);
+            user.bot = bot;
+            user
+        };
+
+        assert!(!is_from_bot(&user(2, false), bot_id));
+        assert!(is_from_bot(&user(3, true), bot_id));
+        assert!(is_from_bot(&user(10, false), bot_id));
+        assert!(is_from_bot(&user(10, true), bot_id));
+    }
+
+    #[test]
+    fn is_channel_allowed_by_allowlist() {
+        let everywhere = config("http://localhost");
+        assert!(everywhere.is_channel_allowed(ChannelId(1)));
+
+        let listed = Config { allowed_channels: HashSet::from([ChannelId(1), ChannelId(2)]), ..config("http://localhost") };
+        assert!(listed.is_channel_allowed(ChannelId(1)));
+        assert!(listed.is_channel_allowed(ChannelId(2)));
+        assert!(!listed.is_channel_allowed(ChannelId(3)));
+    }
+
+    #[tokio::test]
+    async fn openai_user_hashes_id_stably() {
+        let openai = MockOpenAi::start(vec![]);
+        let handler = handler(&openai);
+        let user_id = UserId(123_456_789_012_345_678);
+
+        let user = handler.openai_user(user_id);
+
+        assert_eq!(user, handler.openai_user(user_id));
+        let restarted = Handler::new(Arc::new(config(openai.base_url.as_str())), None, None);
+        assert_eq!(user, restarted.openai_user(user_id), "the hash changed between handlers");
+        assert_ne!(user, handler.openai_user(UserId(user_id.0 + 1)));
+        assert!(!user.contains(user_id.0.to_string().as_str()));
+        assert_eq!(user.len(), 64);
+    }
+
+    #[test]
+    fn apply_prompt_template_wraps_prompt() {
+        assert_eq!(apply_prompt_template(None, "Hello"), "Hello");
+        assert_eq!(apply_prompt_template(Some("Answer concisely. {prompt}"), "Hello"), "Answer concisely. Hello");
+        assert_eq!(apply_prompt_template(Some("{prompt}\n\n{prompt}"), "Hi"), "Hi\n\nHi");
+        // Only the template's placeholder is filled in.
+        assert_eq!(apply_prompt_template(Some("Q: {prompt}"), "What's {prompt}?"), "Q: What's {prompt}?");
+    }
+
+    #[test]
+    fn validate_prompt_template_needs_placeholder() {
+        assert!(validate_prompt_template("Answer concisely. {prompt}").is_ok());
+        assert!(matches!(validate_prompt_template("Answer concisely."), Err(BotError::UserInput(_))));
+    }
+
+    #[test]
+    fn format_reply_chunks_lays_out_each_format() {
+        let plain = format_reply_chunks(ReplyFormat::Plain, "Hello", "Hi there!", "gpt-4", Some("A note."));
+        assert_eq!(plain.len(), 1);
+        assert_eq!(plain[0].content, "> Hello\nHi there!\n\n*A note.*");
+        assert!(plain[0].embed.is_none());
+
+        let code = format_reply_chunks(ReplyFormat::Code, "Hello", "Hi there!", "gpt-4", None);
+        assert_eq!(code.len(), 1);
+        assert_eq!(code[0].content, "> Hello\n```\nHi there!\n```");
+        assert!(code[0].embed.is_none());
+
+        let embed = format_reply_chunks(ReplyFormat::Embed, "Hello", "Hi there!", "gpt-4", None);
+        assert_eq!(embed.len(), 1);
+        assert_eq!(embed[0].content, "> Hello");
+        assert_eq!(embed[0].embed, Some(("Hi there!".to_owned(), "gpt-4".to_owned())));
+
+        let noted = format_reply_chunks(ReplyFormat::Embed, "Hello", "Hi there!", "gpt-4", Some("A note."));
+        assert_eq!(noted[0].embed.as_ref().map(|(_, footer)| footer.as_str()), Some("A note."));
+
+        let empty = format_reply_chunks(ReplyFormat::Embed, "Hello", "  ", "gpt-4", None);
+        assert_eq!(empty[0].embed.as_ref().map(|(description, _)| description.as_str()), Some(EMPTY_RESPONSE_PLACEHOLDER));
+    }
+
+    #[test]
+    fn format_reply_chunks_keeps_embeds_in_limits() {
+        let response = ["word"; 2000].join(" ");
+        let prompt = "p".repeat(3000);
+        let note = "n".repeat(3000);
+
+        let chunks = format_reply_chunks(ReplyFormat::Embed, prompt.as_str(), response.as_str(), "gpt-4", Some(note.as_str()));
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].content.chars().count(), DISCORD_MESSAGE_LIMIT);
+        assert!(chunks[1..].iter().all(|chunk| chunk.content.is_empty()), "the prompt was quoted more than once");
+        for chunk in &chunks {
+            let (description, _) = chunk.embed.as_ref().expect("every chunk to have an embed");
+            assert!(description.chars().count() <= MAX_EMBED_DESCRIPTION_CHARS);
+            assert!(chunk.embed_chars() <= MAX_EMBED_TOTAL_CHARS);
+        }
+        let descriptions: Vec<_> = chunks.iter().filter_map(|chunk| chunk.embed.as_ref().map(|(description, _)| description.as_str())).collect();
+        assert_eq!(descriptions.join(" "), response);
+    }
+
+    #[test]
+    fn metrics_escape_label_values() {
+        let mut metrics = Metrics::default();
+        metrics.record_tokens("my \"model\"\\v2\nnext", TokenUsage { prompt_tokens: 5, completion_tokens: 2, total_tokens: 7 });
+        metrics.record_error("slash");
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains(r#"chatgpt_tokens_total{model="my \"model\"\\v2\nnext",kind="prompt"} 5"#), "{rendered}");
+        assert!(rendered.contains(r#"chatgpt_errors_total{ui="slash"} 1"#), "{rendered}");
+        assert!(rendered.lines().all(|line| line.starts_with('#') || line.starts_with("chatgpt_")), "a label value broke a line: {rendered}");
+    }
 }